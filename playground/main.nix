@@ -28,7 +28,10 @@ let
         this_is_also_a_value = rename_me;
     };
 
-    # Ignoring shadowed variables is currently broken
+    # Inherited variables are split out into a binding of their own
+    inherit rename_me;
+
+    # Shadowed variables are left alone
     shadowed = rec {
       rename_me = "unrelated, so if possible, don't *actually* rename me";
       hopefully_unchanged_usage = rename_me;
@@ -36,5 +39,7 @@ let
   };
 in
 [
+  # Renaming attributes also updates their definition
   inline_set.shared_prefix_for_first_item
+  imported_set.imported_shared_prefix_for_first_item
 ]
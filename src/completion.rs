@@ -1,60 +1,234 @@
-use crate::{utils, App};
+use crate::{
+    documents::Document,
+    utils::{self, Var},
+    App,
+};
 use itertools::Itertools;
 use lsp_types::{
-    CompletionItem, CompletionList, CompletionResponse, CompletionTextEdit, Documentation, Range,
-    TextDocumentPositionParams, TextEdit,
+    CompletionItem, CompletionItemKind, CompletionTextEdit, Documentation, MarkupContent,
+    MarkupKind, Range, TextDocumentPositionParams, TextEdit,
 };
-use manix::{DocEntry, DocSource};
+use manix::{options_docsource::OptionDocumentation, DocEntry, DocSource};
 use rnix::{
-    types::{ParsedType, TokenWrapper, TypedNode},
-    NixLanguage, SyntaxKind, SyntaxNode, TextUnit,
+    types::{Apply, AttrSet, EntryHolder, Key, Pattern, TokenWrapper, TypedNode},
+    SyntaxKind, TextRange, TextUnit,
 };
-use std::convert::TryFrom;
+use std::{collections::HashSet, rc::Rc};
 
 impl App {
     fn scope_completions(
         &mut self,
         params: &TextDocumentPositionParams,
     ) -> Option<Vec<CompletionItem>> {
-        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let Document {
+            ast, code: content, ..
+        } = self.files.get(&params.text_document.uri)?;
         let offset = utils::lookup_pos(content, params.position)?;
         let root_node = ast.node();
+        let uri = params.text_document.uri.clone();
 
-        let (name, scope) =
-            self.scope_for_ident(params.text_document.uri.clone(), &root_node, offset)?;
-        let (_, content) = self.files.get(&params.text_document.uri)?;
+        // Right after a dot there's no identifier yet, only the set
+        let (range, scope) = if let Some(select) = utils::select_before(&root_node, offset) {
+            let path = utils::dotted_path(&select.set()?)?;
+            let range = TextRange::offset_len(TextUnit::from_usize(offset), 0.into());
+            (range, self.scope_for_path(uri, select.node(), &path)?)
+        } else {
+            let (name, scope) = self.scope_for_ident(uri, &root_node, offset)?;
+            (name.node().text_range(), scope)
+        };
+        let content = &self.files.get(&params.text_document.uri)?.code;
+        let typed = content[range.start().to_usize()..range.end().to_usize()].to_owned();
+        let range = utils::range(content, range);
 
-        let scope_completions = scope
-            .keys()
-            .filter(|var| var.starts_with(&name.as_str()))
-            .map(|var| CompletionItem {
-                label: var.clone(),
+        let mut scope_completions = Vec::new();
+        for (name, var) in scope {
+            if name.starts_with(&typed) {
+                scope_completions.push(CompletionItem {
+                    label: name.clone(),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range,
+                        new_text: name,
+                    })),
+                    kind: Some(self.completion_kind(&var)),
+                    ..CompletionItem::default()
+                });
+            }
+        }
+        Some(scope_completions)
+    }
+    /// Complete the arguments a function takes in the set it's called
+    /// with, like `b` in `f { a = 1; }` where `f = { a, b ? 2 }: ...`,
+    /// leaving out the ones already given
+    fn argument_completions(
+        &mut self,
+        params: &TextDocumentPositionParams,
+    ) -> Option<Vec<CompletionItem>> {
+        let Document {
+            ast, code: content, ..
+        } = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(content, params.position)?;
+        let node = utils::closest_node_to(&ast.node(), offset)?;
+
+        // Only keys directly in the set are arguments
+        let (set, key, range) = if let Some(set) = AttrSet::cast(node.clone()) {
+            let cursor = TextUnit::from_usize(offset);
+            (set, None, TextRange::offset_len(cursor, 0.into()))
+        } else {
+            let key = Key::cast(node.parent()?)?;
+            if key.path().next()? != node {
+                return None;
+            }
+            let set = AttrSet::cast(key.node().parent()?.parent()?)?;
+            (set, Some(key.node().clone()), node.text_range())
+        };
+        let typed = content[range.start().to_usize()..range.end().to_usize()].to_owned();
+        let range = utils::range(content, range);
+        let apply = Apply::cast(set.node().parent()?)?;
+        if apply.value()? != *set.node() {
+            return None;
+        }
+
+        let mut given = HashSet::new();
+        for inherit in set.inherits() {
+            given.extend(inherit.idents().map(|ident| ident.as_str().to_owned()));
+        }
+        for entry_key in set.entries().filter_map(|entry| entry.key()) {
+            if Some(entry_key.node()) != key.as_ref() {
+                given.extend(
+                    entry_key
+                        .path()
+                        .next()
+                        .and_then(|name| utils::key_name(&name)),
+                );
+            }
+        }
+
+        let mut file = Rc::new(params.text_document.uri.clone());
+        let lambda = self.function_of(&mut file, &apply)?;
+        let completions = Pattern::cast(lambda.arg()?)?
+            .entries()
+            .filter_map(|entry| Some((entry.name()?.as_str().to_owned(), entry.default())))
+            .filter(|(name, _)| name.starts_with(&typed) && !given.contains(name))
+            .map(|(name, default)| CompletionItem {
+                label: name.clone(),
+                kind: Some(CompletionItemKind::Property),
+                detail: default.map(|default| format!("? {}", default.text())),
                 text_edit: Some(CompletionTextEdit::Edit(TextEdit {
-                    range: utils::range(content, name.node().text_range()),
-                    new_text: var.clone(),
+                    range,
+                    new_text: name,
                 })),
                 ..CompletionItem::default()
             })
-            .collect_vec();
-        Some(scope_completions)
+            .collect();
+        Some(completions)
+    }
+    /// Mark sets as modules and functions as such, so that the attributes
+    /// to complete next stand out
+    fn completion_kind(&mut self, var: &Var) -> CompletionItemKind {
+        // `a.b = 1;` makes `a` a set
+        let key = var.key.parent().and_then(Key::cast);
+        if key.is_some_and(|key| key.path().nth(1).is_some()) {
+            return CompletionItemKind::Module;
+        }
+        let mut file = Rc::clone(&var.file);
+        let value = var
+            .value
+            .clone()
+            .and_then(|value| self.resolve_value(&mut file, value));
+        match value.map(|value| value.kind()) {
+            Some(SyntaxKind::NODE_ATTR_SET) => CompletionItemKind::Module,
+            Some(SyntaxKind::NODE_LAMBDA) => CompletionItemKind::Function,
+            _ => CompletionItemKind::Variable,
+        }
     }
 
+    /// Complete the next segment of an option path being set in a module,
+    /// like `enable` in `services.nginx.en`, including the path of the sets
+    /// the key is in
     fn manix_options_completions(
         &self,
         params: &TextDocumentPositionParams,
     ) -> Option<Vec<CompletionItem>> {
-        // TODO implement this
-        None
+        let document = self.files.get(&params.text_document.uri)?;
+        let offset = utils::lookup_pos(&document.code, params.position)?;
+        let (path, range) = self.option_path_at(document, offset)?;
+        let content = &document.code;
+        let typed = &content[range.start().to_usize()..offset];
+        let query = path.first().map_or(typed, String::as_str);
+        if query.is_empty() {
+            return None;
+        }
+
+        // Attribute names like `<name>` in `virtualHosts.<name>.root` match
+        // anything
+        let matches = |pattern: &str, name: &String| {
+            pattern == name
+                || pattern == "*"
+                || (pattern.starts_with('<') && pattern.ends_with('>'))
+        };
+        let mut completions = Vec::new();
+        for entry in self
+            .manix_options
+            .search(&manix::Lowercase(query.as_bytes()))
+        {
+            let name = entry.name();
+            let segments = name.split('.').collect_vec();
+            if segments.len() <= path.len()
+                || !segments
+                    .iter()
+                    .zip(&path)
+                    .all(|(pattern, name)| matches(pattern, name))
+            {
+                continue;
+            }
+            let label = segments[path.len()];
+            if label.starts_with('<') || label == "*" {
+                continue;
+            }
+            let mut item = CompletionItem {
+                label: label.to_owned(),
+                kind: Some(CompletionItemKind::Module),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                    range: utils::range(content, range),
+                    new_text: label.to_owned(),
+                })),
+                ..CompletionItem::default()
+            };
+            if segments.len() == path.len() + 1 {
+                item.kind = Some(CompletionItemKind::Property);
+                if let DocEntry::OptionDoc(option) = &entry {
+                    option_documentation(&mut item, option);
+                }
+            }
+            completions.push(item);
+        }
+        // Prefer options over sets of the same name, which only happens
+        // when an option has sub-options of its own
+        completions.sort_by_key(|item| item.kind != Some(CompletionItemKind::Property));
+        Some(
+            completions
+                .into_iter()
+                .unique_by(|item| item.label.clone())
+                .collect(),
+        )
     }
 
     fn manix_value_completions(
         &self,
         params: &TextDocumentPositionParams,
     ) -> Option<Vec<CompletionItem>> {
-        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let document = self.files.get(&params.text_document.uri)?;
+        let Document {
+            ast, code: content, ..
+        } = document;
         let offset = utils::lookup_pos(content, params.position)?;
         let root_node = ast.node();
 
+        // Keys in modules are option paths instead
+        if self.option_path_at(document, offset).is_some() {
+            return None;
+        }
+
         let node = utils::closest_node_to(&root_node, offset)?;
         let (full_ident_node, full_ident_name) = self.full_ident_name(&node)?;
         dbg!(node.text_range());
@@ -85,44 +259,74 @@ impl App {
             ),
         };
 
-        let search_results = self.manix_values.search(&manix::Lowercase(
-            &full_ident_name.clone().join(".").as_bytes(),
-        ));
+        // Names brought into scope by `with pkgs;` or `with lib;` are
+        // completed without the prefix
+        let mut names = Vec::new();
+        if Key::cast(full_ident_node.clone()).is_none() {
+            let file = Rc::new(params.text_document.uri.clone());
+            names = utils::with_names(&file, &full_ident_node, &full_ident_name);
+        }
+        names.push((full_ident_name, 0));
 
-        let (namespace, namespace_items) =
-            self.next_namespace_step_completions(full_ident_name.clone(), search_results);
+        let mut manix_completions = Vec::new();
+        for (name, prefix_len) in names {
+            let search_results = self
+                .manix_values
+                .search(&manix::Lowercase(name.join(".").as_bytes()));
 
-        let manix_completions = namespace_items
-            .iter()
-            .unique_by(|x| x.name())
-            .map(|def| CompletionItem {
-                label: def.name().clone(),
-                text_edit: Some(CompletionTextEdit::Edit(TextEdit {
-                    range: node_range,
-                    new_text: def.name().clone(),
-                })),
-                documentation: def
-                    .try_as_doc_entry()
-                    .map(|entry| Documentation::String(entry.pretty_printed())),
-                ..CompletionItem::default()
-            })
+            let (_, namespace_items) = self.next_namespace_step_completions(name, search_results);
+
+            for def in namespace_items.iter().unique_by(|x| x.name()) {
+                let label = def.name().split('.').skip(prefix_len).join(".");
+                let kind = match def {
+                    NamespaceCompletionResult::Set(_) => CompletionItemKind::Module,
+                    NamespaceCompletionResult::FinalNode(_) => CompletionItemKind::Value,
+                };
+                manix_completions.push(CompletionItem {
+                    label: label.clone(),
+                    kind: Some(kind),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range: node_range,
+                        new_text: label,
+                    })),
+                    documentation: def
+                        .try_as_doc_entry()
+                        .map(|entry| Documentation::String(entry.pretty_printed())),
+                    ..CompletionItem::default()
+                });
+            }
+        }
+        let manix_completions = manix_completions
+            .into_iter()
+            .unique_by(|item| item.label.clone())
             .collect_vec();
         Some(manix_completions)
     }
 
-    #[allow(clippy::shadow_unrelated)] // false positive
+    /// Complete the identifier at the cursor from the arguments of a
+    /// function being called, local bindings, then documented values and
+    /// then options. Items are ranked in that order, and earlier ones hide
+    /// anything else of the same name.
     pub fn completions(
         &mut self,
         params: &TextDocumentPositionParams,
     ) -> Option<Vec<CompletionItem>> {
-        // let scope_completions = self.scope_completions(params)?;
-        let mut manix_value_completions = self.manix_value_completions(params).unwrap_or_default();
-        let mut manix_options_completions =
-            self.manix_options_completions(params).unwrap_or_default();
+        let sources = vec![
+            self.argument_completions(params),
+            self.scope_completions(params),
+            self.manix_value_completions(params),
+            self.manix_options_completions(params),
+        ];
+        let mut seen = HashSet::new();
         let mut completions = Vec::new();
-        completions.append(&mut manix_value_completions);
-        completions.append(&mut manix_options_completions);
-
+        for (rank, items) in sources.into_iter().enumerate() {
+            for mut item in items.into_iter().flatten() {
+                if seen.insert(item.label.clone()) {
+                    item.sort_text = Some(format!("{rank}{}", item.label));
+                    completions.push(item);
+                }
+            }
+        }
         Some(completions)
     }
 
@@ -131,8 +335,6 @@ impl App {
         current_ns: Vec<String>,
         search_results: Vec<DocEntry>,
     ) -> (Vec<String>, Vec<NamespaceCompletionResult>) {
-        // TODO handle things like `with pkgs;`
-
         let query_ns_iter = current_ns.iter();
         let longest_match = search_results
             .iter()
@@ -201,3 +403,24 @@ impl NamespaceCompletionResult {
         }
     }
 }
+
+/// Describe an option with its type and description. The options database
+/// doesn't keep default values, so they can't be shown.
+fn option_documentation(item: &mut CompletionItem, option: &OptionDocumentation) {
+    let Ok(option) = serde_json::to_value(option) else {
+        return;
+    };
+    let option_type = option.get("type").and_then(|t| t.as_str());
+    let description = option.get("description").and_then(|d| d.as_str());
+    item.detail = option_type.map(String::from);
+    item.documentation = description.map(|description| {
+        let value = match option_type {
+            Some(option_type) => format!("*Type:* `{option_type}`\n\n{description}"),
+            None => description.to_owned(),
+        };
+        Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value,
+        })
+    });
+}
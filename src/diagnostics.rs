@@ -0,0 +1,502 @@
+use crate::utils::{self, LineIndex, Var};
+use lsp_types::{
+    Diagnostic, DiagnosticRelatedInformation, DiagnosticSeverity, DiagnosticTag, Location,
+    NumberOrString, Range, Url,
+};
+use rnix::{
+    parser::ParseError,
+    types::{
+        AttrSet, EntryHolder, Ident, Inherit, Lambda, LetIn, PatBind, Pattern, TokenWrapper,
+        TypedNode, With,
+    },
+    NodeOrToken, SyntaxKind, SyntaxNode, SyntaxToken, WalkEvent, AST,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+/// Variables which are in scope everywhere without being bound
+const GLOBALS: &[&str] = &[
+    "abort",
+    "baseNameOf",
+    "builtins",
+    "derivation",
+    "derivationStrict",
+    "dirOf",
+    "false",
+    "fetchGit",
+    "fetchMercurial",
+    "fetchTarball",
+    "fetchTree",
+    "fromTOML",
+    "import",
+    "isNull",
+    "map",
+    "null",
+    "placeholder",
+    "removeAttrs",
+    "scopedImport",
+    "throw",
+    "toString",
+    "true",
+];
+
+/// Returns true if the identifier is a builtin which is always in scope
+pub fn is_global(name: &str) -> bool {
+    // All builtins are also available with a __ prefix, e.g. __head
+    GLOBALS.contains(&name) || name.starts_with("__")
+}
+
+/// Returns true if the node is in the body of a `with` expression, which
+/// could bring any variable into scope
+pub fn is_under_with(node: &SyntaxNode) -> bool {
+    node.ancestors().filter_map(With::cast).any(|with| {
+        with.namespace()
+            .is_none_or(|namespace| !node.ancestors().any(|ancestor| ancestor == namespace))
+    })
+}
+
+fn error_code(code: &str) -> NumberOrString {
+    NumberOrString::String(code.into())
+}
+
+/// Return the token which closes a delimiter, if it is an opening one
+fn closing_delimiter(kind: SyntaxKind) -> Option<SyntaxKind> {
+    match kind {
+        SyntaxKind::TOKEN_CURLY_B_OPEN => Some(SyntaxKind::TOKEN_CURLY_B_CLOSE),
+        SyntaxKind::TOKEN_SQUARE_B_OPEN => Some(SyntaxKind::TOKEN_SQUARE_B_CLOSE),
+        SyntaxKind::TOKEN_PAREN_OPEN => Some(SyntaxKind::TOKEN_PAREN_CLOSE),
+        SyntaxKind::TOKEN_STRING_START => Some(SyntaxKind::TOKEN_STRING_END),
+        SyntaxKind::TOKEN_INTERPOL_START => Some(SyntaxKind::TOKEN_INTERPOL_END),
+        SyntaxKind::TOKEN_DYNAMIC_START => Some(SyntaxKind::TOKEN_DYNAMIC_END),
+        _ => None,
+    }
+}
+
+/// Return a human readable description of a token kind
+fn describe(kind: SyntaxKind) -> String {
+    let text = match kind {
+        SyntaxKind::TOKEN_CURLY_B_OPEN => "{",
+        SyntaxKind::TOKEN_CURLY_B_CLOSE
+        | SyntaxKind::TOKEN_INTERPOL_END
+        | SyntaxKind::TOKEN_DYNAMIC_END => "}",
+        SyntaxKind::TOKEN_SQUARE_B_OPEN => "[",
+        SyntaxKind::TOKEN_SQUARE_B_CLOSE => "]",
+        SyntaxKind::TOKEN_PAREN_OPEN => "(",
+        SyntaxKind::TOKEN_PAREN_CLOSE => ")",
+        SyntaxKind::TOKEN_INTERPOL_START | SyntaxKind::TOKEN_DYNAMIC_START => "${",
+        SyntaxKind::TOKEN_ASSIGN => "=",
+        SyntaxKind::TOKEN_COLON => ":",
+        SyntaxKind::TOKEN_COMMA => ",",
+        SyntaxKind::TOKEN_DOT => ".",
+        SyntaxKind::TOKEN_SEMICOLON => ";",
+        SyntaxKind::TOKEN_IN => "in",
+        SyntaxKind::TOKEN_THEN => "then",
+        SyntaxKind::TOKEN_ELSE => "else",
+        SyntaxKind::TOKEN_IDENT => return "an identifier".into(),
+        SyntaxKind::TOKEN_STRING_START | SyntaxKind::TOKEN_STRING_END => {
+            return "end of string".into()
+        }
+        SyntaxKind::TOKEN_STRING_CONTENT => return "string content".into(),
+        _ => return format!("{kind:?}"),
+    };
+    format!("`{text}`")
+}
+
+/// Return all delimiters which are opened but never closed, innermost last
+fn unclosed_delimiters(root: &SyntaxNode) -> Vec<SyntaxToken> {
+    let mut stack: Vec<SyntaxToken> = Vec::new();
+    for token in root
+        .descendants_with_tokens()
+        .filter_map(NodeOrToken::into_token)
+    {
+        if closing_delimiter(token.kind()).is_some() {
+            stack.push(token);
+        } else if let Some(index) = stack
+            .iter()
+            .rposition(|open| closing_delimiter(open.kind()) == Some(token.kind()))
+        {
+            stack.truncate(index);
+        }
+    }
+    stack
+}
+
+fn opened_here(uri: &Url, lines: &LineIndex, open: &SyntaxToken) -> DiagnosticRelatedInformation {
+    DiagnosticRelatedInformation {
+        location: Location {
+            uri: uri.clone(),
+            range: lines.range(open.text_range()),
+        },
+        message: format!("unclosed `{}` opened here", open.text()),
+    }
+}
+
+/// Report all errors rnix found while parsing
+pub fn parse_errors(uri: &Url, lines: &LineIndex, ast: &AST) -> Vec<Diagnostic> {
+    let code = lines.code;
+    let end = lines.position(code.len());
+    let eof = Range { start: end, end };
+    let mut unclosed = unclosed_delimiters(&ast.node());
+
+    let mut diagnostics = Vec::new();
+    for err in ast.errors() {
+        let diagnostic = match err {
+            ParseError::Unexpected(range) => {
+                let text = &code[range.start().to_usize()..range.end().to_usize()];
+                let message = if text.trim().is_empty() || text.contains('\n') || text.len() > 40 {
+                    String::from("unexpected input")
+                } else {
+                    format!("unexpected `{}`", text.trim())
+                };
+                Diagnostic {
+                    range: lines.range(range),
+                    code: Some(error_code("unexpected")),
+                    message,
+                    ..Diagnostic::default()
+                }
+            }
+            ParseError::UnexpectedEOF => Diagnostic {
+                range: eof,
+                code: Some(error_code("unexpected-eof")),
+                related_information: unclosed
+                    .pop()
+                    .map(|open| vec![opened_here(uri, lines, &open)]),
+                message: String::from("unexpected end of file"),
+                ..Diagnostic::default()
+            },
+            ParseError::UnexpectedEOFWanted(kinds) => {
+                let opener = unclosed
+                    .iter()
+                    .rposition(|open| {
+                        closing_delimiter(open.kind()).is_some_and(|close| kinds.contains(&close))
+                    })
+                    .map(|index| unclosed.remove(index));
+                let wanted = kinds.iter().map(|kind| describe(*kind)).collect::<Vec<_>>();
+                let message = format!("unexpected end of file, expected {}", wanted.join(" or "));
+                Diagnostic {
+                    range: eof,
+                    code: Some(error_code("unexpected-eof")),
+                    related_information: opener.map(|open| vec![opened_here(uri, lines, &open)]),
+                    message,
+                    ..Diagnostic::default()
+                }
+            }
+        };
+        diagnostics.push(Diagnostic {
+            severity: Some(DiagnosticSeverity::Error),
+            ..diagnostic
+        });
+    }
+    diagnostics
+}
+
+/// Report arguments which are bound more than once by the same lambda
+pub fn duplicated_arguments(uri: &Url, lines: &LineIndex, root: &SyntaxNode) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for pattern in root.descendants().filter_map(Pattern::cast) {
+        let bind = pattern
+            .node()
+            .children()
+            .find_map(PatBind::cast)
+            .and_then(|bind| bind.name());
+        let mut names = bind
+            .into_iter()
+            .chain(pattern.entries().filter_map(|entry| entry.name()))
+            .collect::<Vec<_>>();
+        names.sort_by_key(|ident| ident.node().text_range().start());
+        let mut seen: HashMap<String, Ident> = HashMap::new();
+        for ident in names {
+            let Some(first) = seen.get(ident.as_str()) else {
+                seen.insert(ident.as_str().into(), ident);
+                continue;
+            };
+            diagnostics.push(Diagnostic {
+                range: lines.range(ident.node().text_range()),
+                severity: Some(DiagnosticSeverity::Error),
+                code: Some(error_code("duplicated-argument")),
+                message: format!("duplicated function argument '{}'", ident.as_str()),
+                related_information: Some(vec![DiagnosticRelatedInformation {
+                    location: Location {
+                        uri: uri.clone(),
+                        range: lines.range(first.node().text_range()),
+                    },
+                    message: String::from("first bound here"),
+                }]),
+                ..Diagnostic::default()
+            });
+        }
+    }
+    diagnostics
+}
+
+/// Resolve every variable reference within a node to the binding it
+/// refers to, if any. The tree is walked once, keeping the bindings of all
+/// enclosing scopes on a stack.
+pub fn resolve_references(file: &Rc<Url>, root: &SyntaxNode) -> Vec<(Ident, Option<Var>)> {
+    let mut scopes: Vec<(SyntaxNode, HashMap<String, Var>)> = Vec::new();
+    let mut references = Vec::new();
+    for event in root.preorder() {
+        let node = match event {
+            WalkEvent::Enter(node) => node,
+            WalkEvent::Leave(node) => {
+                if scopes.last().is_some_and(|(scope, _)| *scope == node) {
+                    scopes.pop();
+                }
+                continue;
+            }
+        };
+        if utils::is_scope(&node) {
+            let entries = utils::entries_of(file, &node).unwrap_or_default();
+            scopes.push((node, entries));
+            continue;
+        }
+        let Some(ident) = Ident::cast(node) else {
+            continue;
+        };
+        if !utils::is_reference(&ident) {
+            continue;
+        }
+        // Identifiers in `inherit x;` don't see the bindings made by the
+        // set or let-block the inherit is in
+        let holder = ident
+            .node()
+            .parent()
+            .and_then(Inherit::cast)
+            .and_then(|inherit| inherit.node().parent());
+        let mut visible = scopes.iter().rev().peekable();
+        if holder.is_some() && visible.peek().map(|(scope, _)| scope) == holder.as_ref() {
+            visible.next();
+        }
+        let var = visible
+            .find_map(|(_, vars)| vars.get(ident.as_str()))
+            .cloned();
+        references.push((ident, var));
+    }
+    references
+}
+
+/// Report variables which aren't bound by anything in scope
+pub fn undefined_variables(
+    lines: &LineIndex,
+    references: &[(Ident, Option<Var>)],
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for (ident, var) in references {
+        let name = ident.as_str();
+        if var.is_some() || is_global(name) || is_under_with(ident.node()) {
+            continue;
+        }
+        diagnostics.push(Diagnostic {
+            range: lines.range(ident.node().text_range()),
+            severity: Some(DiagnosticSeverity::Error),
+            code: Some(error_code("undefined-variable")),
+            message: format!("undefined variable '{name}'"),
+            ..Diagnostic::default()
+        });
+    }
+    diagnostics
+}
+
+/// Report `let` bindings and lambda arguments which are never used.
+/// Names starting with an underscore are never reported.
+pub fn unused_bindings(
+    lines: &LineIndex,
+    root: &SyntaxNode,
+    references: &[(Ident, Option<Var>)],
+) -> Vec<Diagnostic> {
+    let used = references
+        .iter()
+        .filter_map(|(ident, var)| Some((var.as_ref()?.set.clone(), ident.as_str().to_owned())))
+        .collect::<HashSet<_>>();
+
+    let mut diagnostics = Vec::new();
+    for node in root.descendants() {
+        let (kind, bindings) = if let Some(let_in) = LetIn::cast(node.clone()) {
+            let inherited = let_in.inherits().flat_map(|inherit| inherit.idents());
+            let keys = let_in
+                .entries()
+                .filter_map(|entry| entry.key()?.path().next().and_then(Ident::cast));
+            ("binding", inherited.chain(keys).collect::<Vec<_>>())
+        } else if let Some(lambda) = Lambda::cast(node.clone()) {
+            let Some(pattern) = lambda.arg().and_then(Pattern::cast) else {
+                continue;
+            };
+            let entries = pattern.entries().filter_map(|entry| entry.name());
+            ("argument", entries.collect())
+        } else {
+            continue;
+        };
+
+        let mut seen = HashSet::new();
+        for ident in bindings {
+            let name = ident.as_str();
+            if name.starts_with('_') || !seen.insert(name.to_owned()) {
+                continue;
+            }
+            if used.contains(&(node.clone(), name.to_owned())) {
+                continue;
+            }
+            diagnostics.push(Diagnostic {
+                range: lines.range(ident.node().text_range()),
+                severity: Some(DiagnosticSeverity::Warning),
+                code: Some(error_code("unused-binding")),
+                message: format!("unused {kind} '{name}'"),
+                tags: Some(vec![DiagnosticTag::Unnecessary]),
+                ..Diagnostic::default()
+            });
+        }
+    }
+    diagnostics
+}
+
+/// An attribute defined in a set or let-block, used to find duplicates
+enum Attr {
+    Value(SyntaxNode),
+    Set {
+        first: SyntaxNode,
+        attrs: HashMap<String, Attr>,
+    },
+}
+impl Attr {
+    fn first(&self) -> &SyntaxNode {
+        match self {
+            Attr::Value(first) | Attr::Set { first, .. } => first,
+        }
+    }
+}
+
+struct Duplicates<'a> {
+    uri: &'a Url,
+    lines: &'a LineIndex<'a>,
+    diagnostics: Vec<Diagnostic>,
+    /// Sets and let-blocks whose entries were already checked, including
+    /// sets nested in another set's entries
+    walked: HashSet<SyntaxNode>,
+}
+impl Duplicates<'_> {
+    fn report(&mut self, node: &SyntaxNode, first: &SyntaxNode, path: &[String]) {
+        self.diagnostics.push(Diagnostic {
+            range: self.lines.range(node.text_range()),
+            severity: Some(DiagnosticSeverity::Error),
+            code: Some(error_code("duplicate-attribute")),
+            message: format!("attribute '{}' is already defined", path.join(".")),
+            related_information: Some(vec![DiagnosticRelatedInformation {
+                location: Location {
+                    uri: self.uri.clone(),
+                    range: self.lines.range(first.text_range()),
+                },
+                message: String::from("first defined here"),
+            }]),
+            ..Diagnostic::default()
+        });
+    }
+    fn insert_entries<T: EntryHolder>(
+        &mut self,
+        attrs: &mut HashMap<String, Attr>,
+        holder: &T,
+        path: &[String],
+    ) {
+        self.walked.insert(holder.node().clone());
+        for ident in holder.inherits().flat_map(|inherit| inherit.idents()) {
+            let segment = (ident.as_str().to_owned(), ident.node().clone());
+            self.insert(attrs, path.to_vec(), &[segment], None);
+        }
+        for entry in holder.entries() {
+            let Some(key) = entry.key() else {
+                continue;
+            };
+            // Dynamic keys can't be checked statically, so skip the whole entry
+            let segments = key
+                .path()
+                .map(|node| Some((utils::key_name(&node)?, node)))
+                .collect::<Option<Vec<_>>>();
+            if let Some(segments) = segments {
+                self.insert(attrs, path.to_vec(), &segments, entry.value());
+            }
+        }
+    }
+    fn insert(
+        &mut self,
+        attrs: &mut HashMap<String, Attr>,
+        mut path: Vec<String>,
+        segments: &[(String, SyntaxNode)],
+        value: Option<SyntaxNode>,
+    ) {
+        let Some(((name, node), rest)) = segments.split_first() else {
+            return;
+        };
+        path.push(name.clone());
+
+        if rest.is_empty() {
+            let literal = value.and_then(AttrSet::cast).filter(|set| !set.recursive());
+            match (attrs.get_mut(name), literal) {
+                (None, Some(set)) => {
+                    let mut inner = HashMap::new();
+                    self.insert_entries(&mut inner, &set, &path);
+                    attrs.insert(
+                        name.clone(),
+                        Attr::Set {
+                            first: node.clone(),
+                            attrs: inner,
+                        },
+                    );
+                }
+                (None, None) => {
+                    attrs.insert(name.clone(), Attr::Value(node.clone()));
+                }
+                (Some(Attr::Set { attrs: inner, .. }), Some(set)) => {
+                    // Both `a.b = 1; a = { c = 2; };` and
+                    // `a = { b = 1; }; a = { c = 2; };` merge into one set
+                    self.insert_entries(inner, &set, &path);
+                }
+                (Some(existing), _) => {
+                    let first = existing.first().clone();
+                    self.report(node, &first, &path);
+                }
+            }
+            return;
+        }
+
+        let attr = attrs.entry(name.clone()).or_insert_with(|| Attr::Set {
+            first: node.clone(),
+            attrs: HashMap::new(),
+        });
+        match attr {
+            Attr::Set { attrs: inner, .. } => self.insert(inner, path, rest, value),
+            Attr::Value(first) => {
+                let first = first.clone();
+                self.report(node, &first, &path);
+            }
+        }
+    }
+}
+
+/// Report attributes which are defined more than once in the same set or
+/// let-block, including through dotted attribute paths
+pub fn duplicate_attributes(uri: &Url, lines: &LineIndex, root: &SyntaxNode) -> Vec<Diagnostic> {
+    let mut duplicates = Duplicates {
+        uri,
+        lines,
+        diagnostics: Vec::new(),
+        walked: HashSet::new(),
+    };
+    for node in root.descendants() {
+        // Plain sets which are the value of another set's entry are
+        // checked as part of their parent, since their keys merge with
+        // dotted paths defined there. Entries with dynamic keys or which
+        // are duplicates themselves aren't walked, so their sets are
+        // checked on their own.
+        if duplicates.walked.contains(&node) {
+            continue;
+        }
+        let mut attrs = HashMap::new();
+        if let Some(set) = AttrSet::cast(node.clone()) {
+            duplicates.insert_entries(&mut attrs, &set, &[]);
+        } else if let Some(let_in) = LetIn::cast(node) {
+            duplicates.insert_entries(&mut attrs, &let_in, &[]);
+        }
+    }
+    duplicates.diagnostics
+}
@@ -0,0 +1,111 @@
+use crate::{ext::FileKind, utils};
+use lsp_types::Url;
+use rnix::AST;
+use std::{cell::Cell, collections::HashMap, fs, time::SystemTime};
+
+/// Where the contents of a document came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// Opened in the editor, which owns the contents until it's closed
+    Editor(i64),
+    /// Read from disk, for example when following an `import`
+    Disk(Option<SystemTime>),
+}
+
+pub struct Document {
+    pub ast: AST,
+    pub code: String,
+    pub origin: Origin,
+    /// Whether this is a module, detected the first time it's needed
+    pub kind: Cell<Option<FileKind>>,
+}
+impl Document {
+    pub fn new(code: String, origin: Origin) -> Self {
+        Self {
+            ast: rnix::parse(&code),
+            code,
+            origin,
+            kind: Cell::new(None),
+        }
+    }
+    /// The LSP version of this document, if it's open in the editor
+    pub fn version(&self) -> Option<i64> {
+        match self.origin {
+            Origin::Editor(version) => Some(version),
+            Origin::Disk(_) => None,
+        }
+    }
+    pub fn is_open(&self) -> bool {
+        self.version().is_some()
+    }
+}
+
+/// All documents the server knows about, both editor buffers and files
+/// that were lazily read from disk.
+#[derive(Default)]
+pub struct Documents {
+    files: HashMap<Url, Document>,
+}
+impl Documents {
+    pub fn get(&self, uri: &Url) -> Option<&Document> {
+        self.files.get(uri)
+    }
+    /// Store a document, replacing anything that was previously known
+    /// about the file
+    pub fn insert(&mut self, uri: Url, document: Document) {
+        self.files.insert(uri, document);
+    }
+    /// Stop tracking an editor buffer. Returns false if the file was not
+    /// open in the first place.
+    pub fn close(&mut self, uri: &Url) -> bool {
+        let open = self.files.get(uri).is_some_and(Document::is_open);
+        if open {
+            self.files.remove(uri);
+        }
+        open
+    }
+    /// Forget all files which were read from disk, they will be loaded
+    /// again the next time they are needed
+    pub fn evict_disk(&mut self) {
+        self.files.retain(|_, document| document.is_open());
+    }
+    /// Forget a file that was read from disk. Returns false if the file
+    /// was not cached, or is owned by the editor.
+    pub fn invalidate(&mut self, uri: &Url) -> bool {
+        let cached = self
+            .files
+            .get(uri)
+            .is_some_and(|document| !document.is_open());
+        if cached {
+            self.files.remove(uri);
+        }
+        cached
+    }
+    /// Iterate over all documents which are open in the editor
+    pub fn open_documents(&self) -> impl Iterator<Item = (&Url, &Document)> {
+        self.files.iter().filter(|(_, document)| document.is_open())
+    }
+    /// Return the document for a file, reading it from disk if it's not
+    /// open in the editor. Files from disk are read again if they have
+    /// been modified since they were last read.
+    pub fn load(&mut self, uri: &Url) -> Option<&Document> {
+        let loaded = match self.files.get(uri).map(|document| document.origin) {
+            Some(Origin::Editor(_)) => return self.files.get(uri),
+            Some(Origin::Disk(loaded)) => loaded,
+            None => None,
+        };
+        let path = utils::uri_path(uri)?;
+        let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+        let stale = loaded.is_none() || loaded != modified;
+        if stale {
+            if let Ok(code) = fs::read_to_string(&path) {
+                self.files
+                    .insert(uri.clone(), Document::new(code, Origin::Disk(modified)));
+            } else {
+                self.files.remove(uri);
+                return None;
+            }
+        }
+        self.files.get(uri)
+    }
+}
@@ -0,0 +1,52 @@
+//! Requests specific to rnix-lsp, for editor plugins and other tooling
+
+use lsp_types::{request::Request, Location, TextDocumentIdentifier};
+use serde::{Deserialize, Serialize};
+
+/// List the locations of all `import` and `callPackage` expressions which
+/// refer to a file
+pub enum Importers {}
+impl Request for Importers {
+    type Params = TextDocumentIdentifier;
+    type Result = Vec<Location>;
+    const METHOD: &'static str = "rnix/importers";
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphFormat {
+    #[default]
+    Json,
+    Dot,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ImportGraphParams {
+    #[serde(default)]
+    pub format: GraphFormat,
+}
+
+/// Dump the import graph of every indexed file, either as JSON or as a
+/// string in the DOT format used by Graphviz
+pub enum ImportGraph {}
+impl Request for ImportGraph {
+    type Params = ImportGraphParams;
+    type Result = serde_json::Value;
+    const METHOD: &'static str = "rnix/importGraph";
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    /// A NixOS or home-manager module, where keys are option paths
+    Module,
+    Expression,
+}
+
+/// Tell whether a file looks like a NixOS or home-manager module
+pub enum FileKindRequest {}
+impl Request for FileKindRequest {
+    type Params = TextDocumentIdentifier;
+    type Result = FileKind;
+    const METHOD: &'static str = "rnix/fileKind";
+}
@@ -3,11 +3,10 @@ use crate::{
     App,
 };
 use lsp_types::Url;
-use rnix::{types::*, value::Value as ParsedValue, NodeOrToken, SyntaxKind, SyntaxNode};
+use rnix::{types::*, NodeOrToken, SyntaxKind, SyntaxNode, TextRange};
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{HashMap, HashSet},
     convert::TryFrom,
-    fs,
     rc::Rc,
 };
 
@@ -18,15 +17,167 @@ impl App {
         root: &SyntaxNode,
         offset: usize,
     ) -> Option<(Ident, HashMap<String, Var>)> {
-        let mut file = Rc::new(file);
         let info = utils::ident_at(&root, offset)?;
-        let ident = info.ident;
-        let mut entries = utils::scope_for(&file, ident.node().clone())?;
-        for var in info.path {
-            let node = entries.get(&var)?.value.clone()?;
+        let entries = self.scope_for_path(file, info.ident.node(), &info.path)?;
+        Some((info.ident, entries))
+    }
+    /// Return the attributes of the set a dotted path like `a.b` refers to
+    /// at the node, or the variables in scope if the path is empty
+    pub fn scope_for_path(
+        &mut self,
+        file: Url,
+        node: &SyntaxNode,
+        path: &[String],
+    ) -> Option<HashMap<String, Var>> {
+        let mut file = Rc::new(file);
+        let mut entries = utils::scope_for(&file, node.clone());
+        self.add_with_scope(&file, node, &mut entries);
+        for var in path {
+            let node = entries.get(var)?.value.clone()?;
             entries = self.scope_from_node(&mut file, node)?;
         }
-        Some((Ident::cast(ident.node().clone()).unwrap(), entries))
+        Some(entries)
+    }
+    /// Resolve an identifier to the variable or attribute it refers to, or
+    /// to the one it defines if it's a binding itself
+    pub fn resolve_ident(&mut self, file: Url, ident: &Ident) -> Option<Var> {
+        let mut file = Rc::new(file);
+        let name = ident.as_str();
+        let parent = ident.node().parent()?;
+
+        if let Some(key) = Key::cast(parent.clone()) {
+            // Only the first segment of a dotted key can be resolved
+            if key.path().next().as_ref() != Some(ident.node()) {
+                return None;
+            }
+            let holder = key.node().parent()?.parent()?;
+            return utils::entries_of(&file, &holder)?.remove(name);
+        }
+        if let Some(from) = Inherit::cast(parent).and_then(|inherit| inherit.from()) {
+            let set = self.value_of(&mut file, from.inner()?)?;
+            return self.scope_from_node(&mut file, set)?.remove(name);
+        }
+
+        if let Some((set, path)) = utils::checked_attr(ident) {
+            let mut scope = self.scope_from_node(&mut file, set)?;
+            for segment in path {
+                let node = scope.get(&utils::key_name(&segment)?)?.value.clone()?;
+                scope = self.scope_from_node(&mut file, node)?;
+            }
+            return scope.remove(name);
+        }
+
+        let info = utils::cursor_info(ident.clone())?;
+        let mut scope = utils::scope_for(&file, utils::reference_scope(ident)?);
+        self.add_with_scope(&file, ident.node(), &mut scope);
+        for attr in info.path {
+            let node = scope.get(&attr)?.value.clone()?;
+            scope = self.scope_from_node(&mut file, node)?;
+        }
+        scope.remove(name)
+    }
+    /// Add the attributes of the sets brought into scope by `with`
+    /// expressions around the node. Lexical bindings take priority over
+    /// them, even from outer scopes, so existing entries are kept.
+    pub fn add_with_scope(
+        &mut self,
+        file: &Rc<Url>,
+        node: &SyntaxNode,
+        scope: &mut HashMap<String, Var>,
+    ) {
+        for with in utils::enclosing_withs(node) {
+            let mut file = Rc::clone(file);
+            let Some(set) = with
+                .namespace()
+                .and_then(|namespace| self.value_of(&mut file, namespace))
+            else {
+                continue;
+            };
+            for (name, var) in self.scope_from_node(&mut file, set).into_iter().flatten() {
+                scope.entry(name).or_insert(var);
+            }
+        }
+    }
+    /// Follow a variable or attribute selection to its value, through any
+    /// other variables it's defined as, or return the node itself if it's
+    /// already a value
+    pub fn value_of(&mut self, file: &mut Rc<Url>, node: SyntaxNode) -> Option<SyntaxNode> {
+        let mut followed = Vec::new();
+        let value = self.follow_variables(file, node, &mut followed);
+        for key in followed {
+            self.following.remove(&key);
+        }
+        value
+    }
+    fn follow_variables(
+        &mut self,
+        file: &mut Rc<Url>,
+        mut node: SyntaxNode,
+        followed: &mut Vec<(Rc<Url>, TextRange)>,
+    ) -> Option<SyntaxNode> {
+        loop {
+            let ident = match ParsedType::try_from(node.clone()) {
+                Ok(ParsedType::Ident(ident)) => ident,
+                Ok(ParsedType::Select(select)) => Ident::cast(select.index()?)?,
+                Ok(ParsedType::Paren(paren)) => {
+                    node = paren.inner()?;
+                    continue;
+                }
+                _ => return Some(node),
+            };
+            // Variables defined in terms of each other, like
+            // `a = b.x; b = a.y;`, have no value
+            let key = (Rc::clone(file), node.text_range());
+            if !self.following.insert(key.clone()) {
+                return None;
+            }
+            followed.push(key);
+            let var = self.resolve_ident((**file).clone(), &ident)?;
+            *file = Rc::clone(&var.file);
+            node = var.value?;
+        }
+    }
+    /// Find all identifiers in open files, the file defining the variable
+    /// and the files importing it, which resolve to the variable
+    pub fn usages(&mut self, target: &Var) -> Vec<(Url, Ident)> {
+        let name = match Ident::cast(target.key.clone()) {
+            Some(ident) => ident.as_str().to_owned(),
+            None => return Vec::new(),
+        };
+        let mut files = self
+            .files
+            .open_documents()
+            .map(|(uri, _)| uri.clone())
+            .collect::<Vec<_>>();
+        // Files importing the target can use it without being open
+        for uri in self
+            .workspace
+            .importers(&target.file)
+            .into_iter()
+            .chain(Some((*target.file).clone()))
+        {
+            if !files.contains(&uri) {
+                files.push(uri);
+            }
+        }
+
+        let mut usages = Vec::new();
+        for uri in files {
+            let root = match self.files.load(&uri) {
+                Some(document) => document.ast.node(),
+                None => continue,
+            };
+            for ident in root.descendants().filter_map(Ident::cast) {
+                if ident.as_str() != name {
+                    continue;
+                }
+                let var = self.resolve_ident(uri.clone(), &ident);
+                if var.is_some_and(|var| var.is_same(target)) {
+                    usages.push((uri.clone(), ident));
+                }
+            }
+        }
+        usages
     }
     pub fn scope_from_node(
         &mut self,
@@ -38,46 +189,98 @@ impl App {
         if let Some(entry) = KeyValue::cast(node.clone()) {
             node = entry.value()?;
         }
-
-        // Resolve simple imports
+        let node = self.value_of(file, node)?;
+        if let Some(set) = AttrSet::cast(self.resolve_value(file, node)?) {
+            utils::populate(&file, &mut scope, &set);
+        }
+        Some(scope)
+    }
+    /// Follow imports and calls to imported functions or `callPackage` to
+    /// the expression they evaluate to. Returns `None` if the files import
+    /// each other in a cycle.
+    pub fn resolve_value(
+        &mut self,
+        file: &mut Rc<Url>,
+        mut node: SyntaxNode,
+    ) -> Option<SyntaxNode> {
+        let mut visited = HashSet::new();
         loop {
-            let apply = match Apply::cast(node.clone()) {
-                None => break,
-                Some(apply) => apply,
-            };
-            if Ident::cast(apply.lambda()?).map_or(true, |ident| ident.as_str() != "import") {
-                break;
+            if !visited.insert((Rc::clone(file), node.text_range())) {
+                return None;
             }
-            let (_anchor, path) = match Value::cast(apply.value()?) {
-                None => break,
-                Some(value) => match value.to_value() {
-                    Ok(ParsedValue::Path(anchor, path)) => (anchor, path),
-                    _ => break,
-                },
-            };
-
-            // TODO use anchor
-            *file = Rc::new(file.join(&path).ok()?);
-            let path = utils::uri_path(&file)?;
-            node = match self.files.entry((**file).clone()) {
-                Entry::Occupied(entry) => {
-                    let (ast, _code) = entry.get();
-                    ast.root().inner()?.clone()
-                }
-                Entry::Vacant(placeholder) => {
-                    let content = fs::read_to_string(&path).ok()?;
-                    let ast = rnix::parse(&content);
-                    let node = ast.root().inner()?.clone();
-                    placeholder.insert((ast, content));
-                    node
+            node = match ParsedType::try_from(node.clone()) {
+                Ok(ParsedType::Paren(paren)) => paren.inner()?,
+                Ok(ParsedType::LetIn(let_in)) => let_in.body()?,
+                Ok(ParsedType::Apply(apply)) => {
+                    match utils::import_target(&self.workspace.nix_path, file, &apply) {
+                        Some(target) => {
+                            *file = Rc::new(target);
+                            self.files.load(&file)?.ast.root().inner()?
+                        }
+                        None => self.called_function(file, &apply)?.body()?,
+                    }
                 }
+                _ => return Some(node),
             };
         }
+    }
 
-        if let Some(set) = AttrSet::cast(node) {
-            utils::populate(&file, &mut scope, &set);
+    /// Return the function called by `callPackage <path> <overrides>` or
+    /// `import <path> <args>`, switching to the file it's defined in
+    pub fn called_function(&mut self, file: &mut Rc<Url>, apply: &Apply) -> Option<Lambda> {
+        let mut function = apply.lambda()?;
+        while let Some(paren) = Paren::cast(function.clone()) {
+            function = paren.inner()?;
         }
-        Some(scope)
+        let call = Apply::cast(function)?;
+        let nix_path = &self.workspace.nix_path;
+        let target = utils::import_target(nix_path, file, &call)
+            .or_else(|| utils::call_package_target(nix_path, file, &call))?;
+        let mut node = self.files.load(&target)?.ast.root().inner()?;
+        while let Some(paren) = Paren::cast(node.clone()) {
+            node = paren.inner()?;
+        }
+        let lambda = Lambda::cast(node)?;
+        *file = Rc::new(target);
+        Some(lambda)
+    }
+    /// Return the function called by an application, if it's a local
+    /// variable or comes from another file
+    pub fn function_of(&mut self, file: &mut Rc<Url>, apply: &Apply) -> Option<Lambda> {
+        if let Some(lambda) = self.called_function(file, apply) {
+            return Some(lambda);
+        }
+        let mut function_file = Rc::clone(file);
+        let value = self.value_of(&mut function_file, apply.lambda()?)?;
+        let lambda = Lambda::cast(self.resolve_value(&mut function_file, value)?)?;
+        *file = function_file;
+        Some(lambda)
+    }
+    /// If the identifier names an attribute passed to a function, like
+    /// `bar` in `callPackage ./foo { bar = 1; }`, return the pattern
+    /// argument it sets
+    pub fn pattern_argument(&mut self, file: Url, ident: &Ident) -> Option<Var> {
+        let key = Key::cast(ident.node().parent()?)?;
+        if key.path().next().as_ref() != Some(ident.node()) {
+            return None;
+        }
+        let set = key.node().parent()?.parent()?;
+        let apply = Apply::cast(set.parent()?)?;
+        if apply.value()? != set {
+            return None;
+        }
+        let mut file = Rc::new(file);
+        let lambda = self.function_of(&mut file, &apply)?;
+        let name = Pattern::cast(lambda.arg()?)?
+            .entries()
+            .filter_map(|entry| entry.name())
+            .find(|name| name.as_str() == ident.as_str())?;
+        Some(Var {
+            file,
+            set: lambda.node().clone(),
+            key: name.node().clone(),
+            value: None,
+        })
     }
 
     pub fn full_ident_name(&self, node: &SyntaxNode) -> Option<(SyntaxNode, Vec<String>)> {
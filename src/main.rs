@@ -22,10 +22,17 @@
 )]
 
 mod completion;
+mod diagnostics;
+mod documents;
+mod ext;
 mod lookup;
+mod modules;
+mod nix_path;
+mod symbols;
 mod utils;
+mod workspace;
 
-use dirs::home_dir;
+use documents::{Document, Documents, Origin};
 use itertools::Itertools;
 use log::{error, trace, warn};
 use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId, Response};
@@ -41,19 +48,16 @@ use manix::{
     xml_docsource, AggregateDocSource, Cache, DocSource,
 };
 use nixpkgs_tree_docsource::NixpkgsTreeDatabase;
-use rnix::{
-    parser::*,
-    types::*,
-    value::{Anchor as RAnchor, Value as RValue},
-    SyntaxNode,
-};
+use rnix::{types::*, SyntaxNode};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
     fs, panic,
-    path::{Path, PathBuf},
+    path::PathBuf,
     process,
     rc::Rc,
 };
+use workspace::Workspace;
 use xml_docsource::XmlFuncDocDatabase;
 
 type Error = Box<dyn std::error::Error>;
@@ -78,7 +82,7 @@ fn real_main() -> Result<(), Error> {
         text_document_sync: Some(TextDocumentSyncCapability::Options(
             TextDocumentSyncOptions {
                 open_close: Some(true),
-                change: Some(TextDocumentSyncKind::Full),
+                change: Some(TextDocumentSyncKind::Incremental),
                 ..TextDocumentSyncOptions::default()
             },
         )),
@@ -86,27 +90,36 @@ fn real_main() -> Result<(), Error> {
             ..CompletionOptions::default()
         }),
         definition_provider: Some(true),
+        references_provider: Some(true),
         document_formatting_provider: Some(true),
+        document_symbol_provider: Some(true),
         document_link_provider: Some(DocumentLinkOptions {
             resolve_provider: Some(false),
             work_done_progress_options: WorkDoneProgressOptions::default(),
         }),
-        rename_provider: Some(RenameProviderCapability::Simple(true)),
+        rename_provider: Some(RenameProviderCapability::Options(RenameOptions {
+            prepare_provider: Some(true),
+            work_done_progress_options: WorkDoneProgressOptions::default(),
+        })),
         selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+        workspace_symbol_provider: Some(true),
         hover_provider: Some(HoverProviderCapability::Simple(true)),
         ..ServerCapabilities::default()
     })
     .unwrap();
 
-    connection.initialize(capabilities)?;
+    let params: InitializeParams = serde_json::from_value(connection.initialize(capabilities)?)?;
 
     let (cache_invalid, manix_values) = load_manix_values().unwrap();
     let manix_options = load_manix_options(cache_invalid).unwrap();
     App {
-        files: HashMap::new(),
+        files: Documents::default(),
+        workspace: Workspace::new(&params),
+        client_capabilities: params.capabilities,
         manix_options,
         manix_values,
         conn: connection,
+        following: HashSet::new(),
     }
     .main();
 
@@ -220,17 +233,25 @@ fn load_manix_options(reload_cache: bool) -> Option<AggregateDocSource> {
 }
 
 struct App {
-    files: HashMap<Url, (AST, String)>,
+    files: Documents,
+    workspace: Workspace,
+    client_capabilities: ClientCapabilities,
     manix_options: manix::AggregateDocSource,
     manix_values: manix::AggregateDocSource,
     conn: Connection,
+    /// Variables whose value is being looked up, to stop at cycles
+    following: HashSet<(Rc<Url>, rnix::TextRange)>,
 }
 impl App {
     fn reply(&mut self, response: Response) {
         trace!("Sending response: {:#?}", response);
         self.conn.sender.send(Message::Response(response)).unwrap();
     }
-    fn notify(&mut self, notification: Notification) {
+    fn request(&mut self, request: Request) {
+        trace!("Sending request: {:#?}", request);
+        self.conn.sender.send(Message::Request(request)).unwrap();
+    }
+    fn notify(&self, notification: Notification) {
         trace!("Sending notification: {:#?}", notification);
         self.conn
             .sender
@@ -249,7 +270,24 @@ impl App {
         ));
     }
     fn main(&mut self) {
-        while let Ok(msg) = self.conn.receiver.recv() {
+        self.register_file_watchers();
+        let messages = self.conn.receiver.clone();
+        let mut events = self.workspace.spawn_indexer();
+        loop {
+            let msg = crossbeam_channel::select! {
+                recv(messages) -> msg => match msg {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+                recv(events) -> event => {
+                    match event {
+                        Ok(event) => self.handle_index_event(event),
+                        // The indexer is done, stop listening to it
+                        Err(_) => events = crossbeam_channel::never(),
+                    }
+                    continue;
+                }
+            };
             trace!("Message: {:#?}", msg);
             match msg {
                 Message::Request(req) => {
@@ -278,6 +316,101 @@ impl App {
             }
         }
     }
+    fn handle_index_event(&mut self, event: workspace::Event) {
+        let progress = self
+            .client_capabilities
+            .window
+            .as_ref()
+            .and_then(|window| window.work_done_progress)
+            .unwrap_or(false);
+        let token = NumberOrString::String(String::from("rnix-lsp/indexing"));
+        let value = match event {
+            workspace::Event::Started(total) => {
+                if progress {
+                    self.request(Request::new(
+                        RequestId::from(String::from("rnix-lsp/indexing")),
+                        WorkDoneProgressCreate::METHOD.into(),
+                        WorkDoneProgressCreateParams {
+                            token: token.clone(),
+                        },
+                    ));
+                }
+                WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                    title: String::from("Indexing"),
+                    cancellable: Some(false),
+                    message: Some(format!("0/{total} files")),
+                    percentage: Some(0.0),
+                })
+            }
+            workspace::Event::Indexed {
+                uri,
+                index,
+                done,
+                total,
+            } => {
+                // Open files were already indexed with the editor's contents
+                if !self.files.get(&uri).is_some_and(Document::is_open) {
+                    self.workspace.insert(uri, index);
+                }
+                let percentage = |done: usize| {
+                    (done * 100)
+                        .checked_div(total)
+                        .and_then(|percentage| u32::try_from(percentage).ok())
+                        .unwrap_or(100)
+                };
+                if percentage(done) == percentage(done - 1) {
+                    return;
+                }
+                WorkDoneProgress::Report(WorkDoneProgressReport {
+                    cancellable: Some(false),
+                    message: Some(format!("{done}/{total} files")),
+                    percentage: Some(f64::from(percentage(done))),
+                })
+            }
+            workspace::Event::Finished => {
+                WorkDoneProgress::End(WorkDoneProgressEnd { message: None })
+            }
+        };
+        if progress {
+            self.notify(Notification::new(
+                Progress::METHOD.into(),
+                ProgressParams {
+                    token,
+                    value: ProgressParamsValue::WorkDone(value),
+                },
+            ));
+        }
+    }
+    fn register_file_watchers(&mut self) {
+        let dynamic = self
+            .client_capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files.as_ref())
+            .and_then(|watched| watched.dynamic_registration)
+            .unwrap_or(false);
+        if !dynamic {
+            return;
+        }
+        let options = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![FileSystemWatcher {
+                glob_pattern: String::from("**/*.nix"),
+                kind: None,
+            }],
+        };
+        self.request(Request::new(
+            RequestId::from(String::from("rnix-lsp/watchFiles")),
+            RegisterCapability::METHOD.into(),
+            RegistrationParams {
+                registrations: vec![Registration {
+                    id: String::from("rnix-lsp/watchFiles"),
+                    method: DidChangeWatchedFiles::METHOD.into(),
+                    register_options: serde_json::to_value(options).ok(),
+                }],
+            },
+        ));
+    }
+    #[allow(clippy::too_many_lines)]
     fn handle_request(&mut self, req: Request) -> Result<(), Error> {
         fn cast<Kind>(req: &mut Option<Request>) -> Option<(RequestId, Kind::Params)>
         where
@@ -299,6 +432,23 @@ impl App {
             } else {
                 self.reply(Response::new_ok(id, ()));
             }
+        } else if let Some((id, params)) = cast::<References>(&mut req) {
+            let locations = self.references(&params).unwrap_or_default();
+            self.reply(Response::new_ok(id, locations));
+        } else if let Some((id, params)) = cast::<ext::Importers>(&mut req) {
+            let importers = self.importers(&params.uri);
+            self.reply(Response::new_ok(id, importers));
+        } else if let Some((id, params)) = cast::<ext::ImportGraph>(&mut req) {
+            let graph = self.workspace.import_graph(params.format);
+            self.reply(Response::new_ok(id, graph));
+        } else if let Some((id, params)) = cast::<ext::FileKindRequest>(&mut req) {
+            let kind = self
+                .files
+                .get(&params.uri)
+                .map_or(ext::FileKind::Expression, |document| {
+                    self.document_kind(document)
+                });
+            self.reply(Response::new_ok(id, kind));
         } else if let Some((id, params)) = cast::<HoverRequest>(&mut req) {
             let documentation = self
                 .documentation(&params.text_document_position_params)
@@ -320,20 +470,37 @@ impl App {
                 .unwrap_or_default();
             // .unwrap_or_else(|| CompletionResponse::Array(Vec::new()));
             self.reply(Response::new_ok(id, completions));
+        } else if let Some((id, params)) = cast::<PrepareRenameRequest>(&mut req) {
+            match self.rename_target(&params) {
+                Ok(target) => {
+                    let range = target.map(|(range, _)| PrepareRenameResponse::Range(range));
+                    self.reply(Response::new_ok(id, range));
+                }
+                Err(err) => self.err(id, err),
+            }
         } else if let Some((id, params)) = cast::<Rename>(&mut req) {
-            let changes = self.rename(params);
-            self.reply(Response::new_ok(
-                id,
-                WorkspaceEdit {
-                    changes,
-                    ..WorkspaceEdit::default()
-                },
-            ));
+            match self.rename(params) {
+                Ok(changes) => {
+                    let edit = changes
+                        .map(|changes| self.workspace_edit(changes))
+                        .unwrap_or_default();
+                    self.reply(Response::new_ok(id, edit));
+                }
+                Err(err) => self.err(id, err),
+            }
         } else if let Some((id, params)) = cast::<DocumentLinkRequest>(&mut req) {
             let document_links = self.document_links(&params).unwrap_or_default();
             self.reply(Response::new_ok(id, document_links));
+        } else if let Some((id, params)) = cast::<DocumentSymbolRequest>(&mut req) {
+            let symbols = self.document_symbols(&params.text_document.uri);
+            self.reply(Response::new_ok(id, symbols));
+        } else if let Some((id, params)) = cast::<WorkspaceSymbol>(&mut req) {
+            let symbols = self.workspace.search(&params.query);
+            self.reply(Response::new_ok(id, symbols));
         } else if let Some((id, params)) = cast::<Formatting>(&mut req) {
-            let changes = if let Some((ast, code)) = self.files.get(&params.text_document.uri) {
+            let changes = if let Some(Document { ast, code, .. }) =
+                self.files.get(&params.text_document.uri)
+            {
                 let fmt = nixpkgs_fmt::reformat_node(&ast.node());
                 fmt.text_diff()
                     .iter()
@@ -349,7 +516,7 @@ impl App {
             self.reply(Response::new_ok(id, changes));
         } else if let Some((id, params)) = cast::<SelectionRangeRequest>(&mut req) {
             let mut selections = Vec::new();
-            if let Some((ast, code)) = self.files.get(&params.text_document.uri) {
+            if let Some(Document { ast, code, .. }) = self.files.get(&params.text_document.uri) {
                 for pos in params.positions {
                     selections.push(utils::selection_ranges(&ast.node(), code, pos));
                 }
@@ -362,18 +529,87 @@ impl App {
         match &*req.method {
             DidOpenTextDocument::METHOD => {
                 let params: DidOpenTextDocumentParams = serde_json::from_value(req.params)?;
-                let text = params.text_document.text;
-                let parsed = rnix::parse(&text);
-                self.send_diagnostics(params.text_document.uri.clone(), &text, &parsed)?;
-                self.files.insert(params.text_document.uri, (parsed, text));
+                let document = Document::new(
+                    params.text_document.text,
+                    Origin::Editor(params.text_document.version),
+                );
+                let uri = params.text_document.uri;
+                self.send_diagnostics(uri.clone(), &document)?;
+                self.workspace
+                    .index(&uri, &document.code, &document.ast.node());
+                self.files.insert(uri, document);
             }
             DidChangeTextDocument::METHOD => {
                 let params: DidChangeTextDocumentParams = serde_json::from_value(req.params)?;
-                if let Some(change) = params.content_changes.into_iter().last() {
-                    let parsed = rnix::parse(&change.text);
-                    self.send_diagnostics(params.text_document.uri.clone(), &change.text, &parsed)?;
-                    self.files
-                        .insert(params.text_document.uri, (parsed, change.text));
+                let uri = params.text_document.uri;
+                let current = self.files.get(&uri).and_then(Document::version);
+                if let (Some(version), Some(current)) = (params.text_document.version, current) {
+                    if version <= current {
+                        warn!(
+                            "Ignoring stale change to {} (version {}, have {})",
+                            uri, version, current
+                        );
+                        return Ok(());
+                    }
+                }
+                let mut text = self
+                    .files
+                    .get(&uri)
+                    .map(|document| document.code.clone())
+                    .unwrap_or_default();
+                for change in params.content_changes {
+                    if utils::apply_change(&mut text, change).is_none() {
+                        warn!("Ignoring change with an invalid range for {}", uri);
+                    }
+                }
+                let version = params.text_document.version.or(current).unwrap_or_default();
+                let document = Document::new(text, Origin::Editor(version));
+                self.send_diagnostics(uri.clone(), &document)?;
+                self.workspace
+                    .index(&uri, &document.code, &document.ast.node());
+                self.files.insert(uri, document);
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams = serde_json::from_value(req.params)?;
+                let uri = params.text_document.uri;
+                if self.files.close(&uri) {
+                    // Unsaved changes are gone, go back to what's on disk
+                    self.workspace.index_disk(&uri);
+                    self.notify(Notification::new(
+                        PublishDiagnostics::METHOD.into(),
+                        PublishDiagnosticsParams {
+                            uri,
+                            diagnostics: Vec::new(),
+                            version: None,
+                        },
+                    ));
+                }
+                self.files.evict_disk();
+            }
+            DidChangeWatchedFiles::METHOD => {
+                let params: DidChangeWatchedFilesParams = serde_json::from_value(req.params)?;
+                let mut changed = Vec::new();
+                for event in params.changes {
+                    if event.typ == FileChangeType::Deleted {
+                        self.workspace.remove(&event.uri);
+                    } else if !self.files.get(&event.uri).is_some_and(Document::is_open) {
+                        self.workspace.index_disk(&event.uri);
+                    }
+                    if self.files.invalidate(&event.uri) {
+                        if event.typ != FileChangeType::Deleted {
+                            self.files.load(&event.uri);
+                        }
+                        changed.push(event.uri);
+                    }
+                }
+                if !changed.is_empty() {
+                    for (uri, document) in self.files.open_documents() {
+                        let imports =
+                            utils::imports(&self.workspace.nix_path, uri, &document.ast.node());
+                        if imports.iter().any(|import| changed.contains(import)) {
+                            self.send_diagnostics(uri.clone(), document)?;
+                        }
+                    }
                 }
             }
             _ => (),
@@ -381,29 +617,141 @@ impl App {
         Ok(())
     }
     fn lookup_definition(&mut self, params: TextDocumentPositionParams) -> Option<Location> {
-        let (current_ast, current_content) = self.files.get(&params.text_document.uri)?;
+        let Document {
+            ast: current_ast,
+            code: current_content,
+            ..
+        } = self.files.get(&params.text_document.uri)?;
         let offset = utils::lookup_pos(current_content, params.position)?;
         let node = current_ast.node();
-        let (name, scope) = self.scope_for_ident(params.text_document.uri, &node, offset)?;
+        if let Some(target) = utils::path_at(&node, offset)
+            .and_then(|path| self.path_file(&params.text_document.uri, &path))
+        {
+            return Some(Location {
+                uri: target,
+                range: Range::default(),
+            });
+        }
+        let (name, mut scope) =
+            self.scope_for_ident(params.text_document.uri.clone(), &node, offset)?;
 
-        let var = scope.get(name.as_str())?;
-        let (_definition_ast, definition_content) = self.files.get(&var.file)?;
+        let var = match self.pattern_argument(params.text_document.uri, &name) {
+            Some(var) => var,
+            None => scope.remove(name.as_str())?,
+        };
+        let definition_content = &self.files.get(&var.file)?.code;
         Some(Location {
             uri: (*var.file).clone(),
             range: utils::range(definition_content, var.key.text_range()),
         })
     }
 
+    fn references(&mut self, params: &ReferenceParams) -> Option<Vec<Location>> {
+        let position = &params.text_document_position;
+        let uri = &position.text_document.uri;
+        let Document { ast, code, .. } = self.files.get(uri)?;
+        let offset = utils::lookup_pos(code, position.position)?;
+        if utils::is_on_root(&ast.node(), offset) {
+            return Some(self.importers(uri));
+        }
+        let info = utils::ident_at(&ast.node(), offset)?;
+        let target = self.resolve_ident(uri.clone(), &info.ident)?;
+
+        let mut locations = Vec::new();
+        for (uri, ident) in self.usages(&target) {
+            let code = &self.files.get(&uri)?.code;
+            locations.push(Location {
+                range: utils::range(code, ident.node().text_range()),
+                uri,
+            });
+        }
+        let code = &self.files.load(&target.file)?.code;
+        let declaration = Location {
+            uri: (*target.file).clone(),
+            range: utils::range(code, target.key.text_range()),
+        };
+        if !params.context.include_declaration {
+            locations.retain(|location| *location != declaration);
+        } else if !locations.contains(&declaration) {
+            // Definitions usually resolve to themselves, except for
+            // `inherit` which refers to the inherited variable instead
+            locations.insert(0, declaration);
+        }
+        Some(locations)
+    }
+    /// Find every `import` or `callPackage` expression referring to a file
+    fn importers(&mut self, uri: &Url) -> Vec<Location> {
+        let mut locations = Vec::new();
+        for importer in self.workspace.importers(uri) {
+            let Some(Document { ast, code, .. }) = self.files.load(&importer) else {
+                continue;
+            };
+            for apply in ast.node().descendants().filter_map(Apply::cast) {
+                let nix_path = &self.workspace.nix_path;
+                let target = utils::import_target(nix_path, &importer, &apply)
+                    .or_else(|| utils::call_package_target(nix_path, &importer, &apply));
+                if target.as_ref() == Some(uri) {
+                    locations.push(Location {
+                        uri: importer.clone(),
+                        range: utils::range(code, apply.node().text_range()),
+                    });
+                }
+            }
+        }
+        locations
+    }
     fn documentation(&mut self, params: &TextDocumentPositionParams) -> Option<String> {
-        let (ast, content) = self.files.get(&params.text_document.uri)?;
+        let document = self.files.get(&params.text_document.uri)?;
+        let Document {
+            ast, code: content, ..
+        } = document;
         let offset = utils::lookup_pos(content, params.position)?;
+        if let Some(path) = utils::path_at(&ast.node(), offset) {
+            let target = self.path_file(&params.text_document.uri, &path)?;
+            return Some(format!("`{}`", utils::uri_path(&target)?.display()));
+        }
+        // Keys in modules are option paths
+        if let Some((mut path, range)) = self.option_path_at(document, offset) {
+            path.push(content[range.start().to_usize()..range.end().to_usize()].to_owned());
+            let name = path.join(".");
+            let options = self
+                .manix_options
+                .search(&manix::Lowercase(name.as_bytes()));
+            return Some(
+                options
+                    .iter()
+                    .filter(|option| option.name() == name)
+                    .map(manix::DocEntry::pretty_printed)
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+            );
+        }
         let cursor = utils::ident_at(&ast.node(), offset)?;
         let ident = cursor.ident.as_str();
 
-        let query = manix::Lowercase(ident.as_bytes());
+        // Look up what `with pkgs;` or `with lib;` could bring into scope
+        // first
+        let mut queries = Vec::new();
+        if cursor.ident.node().parent().and_then(Key::cast).is_none() {
+            let file = Rc::new(params.text_document.uri.clone());
+            let mut path = cursor.path.clone();
+            path.push(ident.to_owned());
+            for (name, _) in utils::with_names(&file, cursor.ident.node(), &path) {
+                queries.push(name.join("."));
+            }
+        }
+        if !queries.iter().any(|query| query == ident) {
+            queries.push(ident.to_owned());
+        }
 
-        let mut definitions = self.manix_values.search(&query);
-        definitions.append(&mut self.manix_options.search(&query));
+        let mut definitions = Vec::new();
+        for query in &queries {
+            definitions.append(
+                &mut self
+                    .manix_values
+                    .search(&manix::Lowercase(query.as_bytes())),
+            );
+        }
 
         Some(
             definitions
@@ -414,109 +762,163 @@ impl App {
         )
     }
 
-    fn rename(&mut self, params: RenameParams) -> Option<HashMap<Url, Vec<TextEdit>>> {
-        struct Rename<'a> {
-            edits: Vec<TextEdit>,
-            code: &'a str,
-            old: &'a str,
-            new_name: String,
+    /// Find the binding to rename at the cursor, along with the range of
+    /// the identifier under it. Returns an error explaining why the
+    /// identifier can't be renamed.
+    fn rename_target(
+        &mut self,
+        params: &TextDocumentPositionParams,
+    ) -> Result<Option<(Range, utils::Var)>, String> {
+        let uri = &params.text_document.uri;
+        let Some(Document { ast, code, .. }) = self.files.get(uri) else {
+            return Ok(None);
+        };
+        let Some(info) = utils::lookup_pos(code, params.position)
+            .and_then(|offset| utils::ident_at(&ast.node(), offset))
+        else {
+            return Ok(None);
+        };
+        let range = utils::range(code, info.ident.node().text_range());
+        let name = info.ident.as_str().to_owned();
+        let key = info.ident.node().parent().and_then(Key::cast);
+        if key.is_some_and(|key| key.path().next().as_ref() != Some(info.ident.node())) {
+            return Err("cannot rename nested segment of a dotted attribute path".into());
         }
-        fn rename_in_node(rename: &mut Rename, node: &SyntaxNode) -> Option<()> {
-            if let Some(ident) = Ident::cast(node.clone()) {
-                if ident.as_str() == rename.old {
-                    rename.edits.push(TextEdit {
-                        range: utils::range(rename.code, node.text_range()),
-                        new_text: rename.new_name.clone(),
-                    });
-                }
-            } else if let Some(index) = Select::cast(node.clone()) {
-                rename_in_node(rename, &index.set()?);
-            } else if let Some(attr) = Key::cast(node.clone()) {
-                let mut path = attr.path();
-                if let Some(ident) = path.next() {
-                    rename_in_node(rename, &ident);
-                }
-            } else {
-                for child in node.children() {
-                    rename_in_node(rename, &child);
-                }
+        let Some(target) = self.resolve_ident(uri.clone(), &info.ident) else {
+            if diagnostics::is_global(&name) {
+                return Err(format!("cannot rename builtin `{name}`"));
             }
-            Some(())
+            return Err(format!("cannot find the definition of `{name}`"));
+        };
+        if Ident::cast(target.key.clone()).is_none() {
+            return Err(format!("cannot rename quoted attribute `{name}`"));
         }
-
-        let uri = params.text_document_position.text_document.uri;
-        let (ast, code) = self.files.get(&uri)?;
-        let offset = utils::lookup_pos(code, params.text_document_position.position)?;
-        let info = utils::ident_at(&ast.node(), offset)?;
-        if !info.path.is_empty() {
-            // Renaming within a set not supported
-            return None;
+        Ok(Some((range, target)))
+    }
+    fn rename(
+        &mut self,
+        params: RenameParams,
+    ) -> Result<Option<HashMap<Url, Vec<TextEdit>>>, String> {
+        let Some((_, target)) = self.rename_target(&params.text_document_position)? else {
+            return Ok(None);
+        };
+        let new_name = params.new_name;
+        if !utils::is_valid_ident(&new_name) {
+            return Err(format!("`{new_name}` is not a valid identifier"));
         }
-        let old = info.ident;
-        let scope = utils::scope_for(&Rc::new(uri.clone()), old.node().clone())?;
-
-        let mut rename = Rename {
-            edits: Vec::new(),
-            code,
-            old: old.as_str(),
-            new_name: params.new_name,
+
+        // Only touch identifiers which resolve to the renamed binding, so
+        // shadowing variables with the same name are left alone
+        let mut idents = self.usages(&target);
+        utils::check_capture(&target, &idents, &new_name)?;
+        let declared = |(file, ident): &(Url, Ident)| {
+            *file == *target.file && ident.node().text_range() == target.key.text_range()
         };
-        let definition = scope.get(old.as_str())?;
-        rename_in_node(&mut rename, &definition.set);
+        let declaration = Ident::cast(target.key.clone());
+        if let Some(declaration) = declaration.filter(|_| !idents.iter().any(declared)) {
+            idents.push(((*target.file).clone(), declaration));
+        }
 
-        let mut changes = HashMap::new();
-        changes.insert(uri, rename.edits);
-        Some(changes)
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        for usage in idents {
+            let Some(document) = self.files.get(&usage.0) else {
+                continue;
+            };
+            let edits = utils::rename_ident(&document.code, &usage.1, declared(&usage), &new_name);
+            changes.entry(usage.0).or_default().extend(edits);
+        }
+        Ok(Some(changes))
+    }
+    fn document_symbols(&self, uri: &Url) -> Option<DocumentSymbolResponse> {
+        let Document { ast, code, .. } = self.files.get(uri)?;
+        let symbols = symbols::document_symbols(code, &ast.node());
+        let hierarchical = self
+            .client_capabilities
+            .text_document
+            .as_ref()
+            .and_then(|text_document| text_document.document_symbol.as_ref())
+            .and_then(|symbol| symbol.hierarchical_document_symbol_support)
+            .unwrap_or(false);
+        if hierarchical {
+            return Some(DocumentSymbolResponse::Nested(symbols));
+        }
+        let mut flat = Vec::new();
+        symbols::flatten(uri, symbols, None, &mut flat);
+        Some(DocumentSymbolResponse::Flat(flat))
+    }
+    /// Return the existing file a path literal refers to
+    fn path_file(&self, uri: &Url, node: &SyntaxNode) -> Option<Url> {
+        utils::path_target(&self.workspace.nix_path, uri, node)
+            .filter(|url| utils::uri_path(url).is_some_and(|path| path.is_file()))
     }
     fn document_links(&mut self, params: &DocumentLinkParams) -> Option<Vec<DocumentLink>> {
-        let (current_ast, current_content) = self.files.get(&params.text_document.uri)?;
-        let parent_dir = Path::new(params.text_document.uri.path()).parent();
-        let home_dir = home_dir();
-        let home_dir = home_dir.as_ref();
+        let Document {
+            ast: current_ast,
+            code: current_content,
+            ..
+        } = self.files.get(&params.text_document.uri)?;
         let mut document_links = vec![];
         for node in current_ast.node().descendants() {
-            let value = Value::cast(node.clone()).and_then(|v| v.to_value().ok());
-            if let Some(RValue::Path(anchor, path)) = value {
-                let file_url = match anchor {
-                    RAnchor::Absolute => Some(PathBuf::from(&path)),
-                    RAnchor::Relative => parent_dir.map(|p| p.join(path)),
-                    RAnchor::Home => home_dir.map(|home| home.join(path)),
-                    RAnchor::Store => None,
-                }
-                .and_then(|path| std::fs::canonicalize(&path).ok())
-                .filter(|path| path.is_file())
-                .and_then(|s| Url::parse(&format!("file://{}", s.to_string_lossy())).ok());
-                if let Some(file_url) = file_url {
-                    document_links.push(DocumentLink {
-                        target: Some(file_url),
-                        range: utils::range(current_content, node.text_range()),
-                        tooltip: None,
-                        data: None,
-                    })
-                }
+            if let Some(file_url) = self.path_file(&params.text_document.uri, &node) {
+                document_links.push(DocumentLink {
+                    target: Some(file_url),
+                    range: utils::range(current_content, node.text_range()),
+                    tooltip: None,
+                    data: None,
+                })
             }
         }
         Some(document_links)
     }
-    fn send_diagnostics(&mut self, uri: Url, code: &str, ast: &AST) -> Result<(), Error> {
-        let errors = ast.errors();
-        let mut diagnostics = Vec::with_capacity(errors.len());
-        for err in errors {
-            if let ParseError::Unexpected(node) = err {
-                diagnostics.push(Diagnostic {
-                    range: utils::range(code, node),
-                    severity: Some(DiagnosticSeverity::Error),
-                    message: err.to_string(),
-                    ..Diagnostic::default()
-                });
-            }
+    fn workspace_edit(&self, changes: HashMap<Url, Vec<TextEdit>>) -> WorkspaceEdit {
+        let document_changes = self
+            .client_capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.workspace_edit.as_ref())
+            .and_then(|edit| edit.document_changes)
+            .unwrap_or(false);
+        if !document_changes {
+            return WorkspaceEdit {
+                changes: Some(changes),
+                ..WorkspaceEdit::default()
+            };
+        }
+        let edits = changes
+            .into_iter()
+            .map(|(uri, edits)| TextDocumentEdit {
+                text_document: VersionedTextDocumentIdentifier {
+                    version: self.files.get(&uri).and_then(Document::version),
+                    uri,
+                },
+                edits,
+            })
+            .collect();
+        WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Edits(edits)),
+            ..WorkspaceEdit::default()
         }
+    }
+    fn send_diagnostics(&self, uri: Url, document: &Document) -> Result<(), Error> {
+        let Document { ast, code, .. } = document;
+        let root = ast.node();
+        let lines = utils::LineIndex::new(code);
+        let mut diagnostics = diagnostics::parse_errors(&uri, &lines, ast);
+        diagnostics.append(&mut diagnostics::duplicated_arguments(&uri, &lines, &root));
+        diagnostics.append(&mut diagnostics::duplicate_attributes(&uri, &lines, &root));
+        let references = diagnostics::resolve_references(&Rc::new(uri.clone()), &root);
+        diagnostics.append(&mut diagnostics::undefined_variables(&lines, &references));
+        diagnostics.append(&mut diagnostics::unused_bindings(
+            &lines,
+            &root,
+            &references,
+        ));
         self.notify(Notification::new(
             "textDocument/publishDiagnostics".into(),
             PublishDiagnosticsParams {
                 uri,
                 diagnostics,
-                version: None,
+                version: document.version(),
             },
         ));
         Ok(())
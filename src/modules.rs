@@ -0,0 +1,120 @@
+use crate::{documents::Document, ext::FileKind, utils, App};
+use manix::DocSource;
+use rnix::{
+    types::{AttrSet, EntryHolder, Key, ParsedType, Root, TypedNode, Wrapper},
+    SyntaxNode, TextRange, TextUnit,
+};
+use std::convert::TryFrom;
+
+/// Top-level attributes only NixOS and home-manager modules have
+const MODULE_KEYS: &[&str] = &["imports", "options", "config"];
+/// How many keys of a set without any of the above need to be options for
+/// it to count as a module. Single names like `users` or `fonts` are common
+/// in other sets too.
+const MIN_OPTION_KEYS: usize = 2;
+
+impl App {
+    /// Guess whether a file is a NixOS or home-manager module. Modules are
+    /// usually functions like `{ config, lib, pkgs, ... }:` returning a set,
+    /// but can be plain sets of options too. Either way, the set itself has
+    /// to look like a module.
+    pub fn file_kind(&self, root: &SyntaxNode) -> FileKind {
+        let mut node = Root::cast(root.clone()).and_then(|root| root.inner());
+        while let Some(current) = node.take() {
+            node = match ParsedType::try_from(current) {
+                Ok(ParsedType::Paren(paren)) => paren.inner(),
+                Ok(ParsedType::LetIn(let_in)) => let_in.body(),
+                Ok(ParsedType::Lambda(lambda)) => lambda.body(),
+                Ok(ParsedType::AttrSet(set)) if self.is_module_set(&set) => {
+                    return FileKind::Module;
+                }
+                _ => None,
+            };
+        }
+        FileKind::Expression
+    }
+    /// The kind of a document, which is only detected once per version as
+    /// it needs to search the options
+    pub fn document_kind(&self, document: &Document) -> FileKind {
+        if let Some(kind) = document.kind.get() {
+            return kind;
+        }
+        let kind = self.file_kind(&document.ast.node());
+        document.kind.set(Some(kind));
+        kind
+    }
+    /// Returns true if the set declares options, imports other modules or
+    /// sets several known options
+    fn is_module_set(&self, set: &AttrSet) -> bool {
+        let paths = set
+            .entries()
+            .filter_map(|entry| {
+                entry
+                    .key()?
+                    .path()
+                    .map(|segment| utils::key_name(&segment))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .collect::<Vec<_>>();
+        if paths
+            .iter()
+            .any(|path| MODULE_KEYS.contains(&path[0].as_str()))
+        {
+            return true;
+        }
+        let is_option = |path: &Vec<String>| {
+            let query = path.join(".");
+            self.manix_options
+                .search(&manix::Lowercase(query.as_bytes()))
+                .iter()
+                .any(|option| {
+                    let name = option.name();
+                    name == query || name.starts_with(&format!("{query}."))
+                })
+        };
+        paths
+            .iter()
+            .filter(|path| is_option(path))
+            .take(MIN_OPTION_KEYS)
+            .count()
+            == MIN_OPTION_KEYS
+    }
+    /// If the cursor is on the key of an entry setting options in a module,
+    /// return the path of the option up to the segment under the cursor,
+    /// along with the range of that segment. The range is empty if the
+    /// cursor is right after a dot.
+    pub fn option_path_at(
+        &self,
+        document: &Document,
+        offset: usize,
+    ) -> Option<(Vec<String>, TextRange)> {
+        if self.document_kind(document) != FileKind::Module {
+            return None;
+        }
+        let root = &document.ast.node();
+        let cursor = TextUnit::from_usize(offset);
+        let key = utils::closest_node_to(root, offset)?
+            .ancestors()
+            .find_map(Key::cast)?;
+        let mut path = self.namespace_for_node(&key.node().parent()?.parent()?);
+        let mut range = TextRange::offset_len(cursor, 0.into());
+        for segment in key.path() {
+            let segment_range = segment.text_range();
+            if segment_range.end() < cursor {
+                path.push(utils::key_name(&segment)?);
+            } else if segment_range.start() <= cursor {
+                range = segment_range;
+            }
+        }
+        // Modules declaring options of their own set everything else under
+        // `config`, and the declarations aren't option paths
+        match path.first().map(String::as_str) {
+            Some("config") => {
+                path.remove(0);
+            }
+            Some("options") => return None,
+            _ => (),
+        }
+        Some((path, range))
+    }
+}
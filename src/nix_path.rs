@@ -0,0 +1,177 @@
+//! Resolution of search paths like `<nixpkgs/lib>`
+
+use serde_json::Value;
+use std::{env, path::PathBuf};
+
+/// A single search path entry, like `nixpkgs=/path/to/nixpkgs` or a bare
+/// directory
+#[derive(Clone, Debug)]
+struct Entry {
+    prefix: Option<String>,
+    path: PathBuf,
+}
+
+/// The directories searched for `<...>` paths, in order
+#[derive(Clone, Debug, Default)]
+pub struct NixPath {
+    entries: Vec<Entry>,
+}
+impl NixPath {
+    /// Parse a search path in the format of `NIX_PATH`. Entries which aren't
+    /// absolute paths, such as URLs and channels, are skipped as they can't
+    /// be resolved locally.
+    pub fn parse(value: &str) -> Self {
+        // URLs contain colons too, so glue `https` and `//host/...` back
+        // together
+        let mut raw: Vec<String> = Vec::new();
+        for part in value.split(':') {
+            match raw.last_mut() {
+                Some(last) if part.starts_with("//") => {
+                    last.push(':');
+                    last.push_str(part);
+                }
+                _ => raw.push(part.to_owned()),
+            }
+        }
+        let entries = raw
+            .into_iter()
+            .map(|entry| match entry.split_once('=') {
+                Some((prefix, path)) => Entry {
+                    prefix: Some(prefix.to_owned()),
+                    path: PathBuf::from(path),
+                },
+                None => Entry {
+                    prefix: None,
+                    path: PathBuf::from(entry),
+                },
+            })
+            .filter(|entry| entry.path.is_absolute())
+            .collect();
+        Self { entries }
+    }
+    /// Read the search path from the `nixPath` setting, which is either a
+    /// string like `NIX_PATH` or a list of entries, followed by the
+    /// `NIX_PATH` environment variable. Like `-I` on the command line, the
+    /// setting takes priority.
+    pub fn new(settings: Option<&Value>) -> Self {
+        let mut entries = match settings.and_then(|settings| settings.get("nixPath")) {
+            Some(Value::String(value)) => Self::parse(value).entries,
+            Some(Value::Array(values)) => values
+                .iter()
+                .filter_map(Value::as_str)
+                .flat_map(|value| Self::parse(value).entries)
+                .collect(),
+            _ => Vec::new(),
+        };
+        if let Ok(value) = env::var("NIX_PATH") {
+            entries.extend(Self::parse(&value).entries);
+        }
+        Self { entries }
+    }
+    /// Find the file or directory a search path like `nixpkgs/lib` refers
+    /// to, using the first entry where it exists
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        self.entries.iter().find_map(|entry| {
+            let candidate = match &entry.prefix {
+                None => entry.path.join(path),
+                Some(prefix) if path == prefix => entry.path.clone(),
+                Some(prefix) => entry
+                    .path
+                    .join(path.strip_prefix(prefix)?.strip_prefix('/')?),
+            };
+            Some(candidate).filter(|candidate| candidate.exists())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::tests::temp_dir;
+    use serde_json::json;
+    use std::{fs, path::Path};
+
+    fn entries(nix_path: &NixPath) -> Vec<(Option<&str>, &Path)> {
+        nix_path
+            .entries
+            .iter()
+            .map(|entry| (entry.prefix.as_deref(), entry.path.as_path()))
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        let nix_path = NixPath::parse(
+            "nixpkgs=/nix/var/nix/profiles/per-user/root/channels/nixpkgs:/etc/nix/path",
+        );
+        assert_eq!(
+            entries(&nix_path),
+            vec![
+                (
+                    Some("nixpkgs"),
+                    Path::new("/nix/var/nix/profiles/per-user/root/channels/nixpkgs")
+                ),
+                (None, Path::new("/etc/nix/path")),
+            ]
+        );
+        // Neither `https` nor `//github.com/...` are entries of their own
+        let nix_path = NixPath::parse(
+            "nixpkgs=https://github.com/NixOS/nixpkgs/archive/master.tar.gz:home=/home/user",
+        );
+        assert_eq!(
+            entries(&nix_path),
+            vec![(Some("home"), Path::new("/home/user"))]
+        );
+        let nix_path = NixPath::parse("channel:nixos-unstable:relative/path:nixpkgs=./nixpkgs::");
+        assert!(entries(&nix_path).is_empty());
+    }
+    #[test]
+    fn resolve() {
+        let dir = temp_dir("nix-path");
+        fs::create_dir_all(dir.join("first/nixpkgs/lib")).unwrap();
+        fs::create_dir_all(dir.join("second/nixpkgs/pkgs")).unwrap();
+        fs::create_dir_all(dir.join("bare/other")).unwrap();
+        let path = |path: &str| Some(dir.join(path));
+
+        let nix_path = NixPath::parse(&format!(
+            "nixpkgs={0}/missing:nixpkgs={0}/first/nixpkgs:{0}/bare:{0}/second",
+            dir.display()
+        ));
+        assert_eq!(nix_path.resolve("nixpkgs"), path("first/nixpkgs"));
+        assert_eq!(nix_path.resolve("nixpkgs/lib"), path("first/nixpkgs/lib"));
+        // Entries where the path doesn't exist are skipped
+        assert_eq!(
+            nix_path.resolve("nixpkgs/pkgs"),
+            path("second/nixpkgs/pkgs")
+        );
+        assert_eq!(nix_path.resolve("other"), path("bare/other"));
+        // Prefixes only match whole segments
+        assert_eq!(nix_path.resolve("nixpkgslib"), None);
+        assert_eq!(nix_path.resolve("missing"), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn settings_before_environment() {
+        let dir = temp_dir("nix-path-settings");
+        for name in &["setting", "array", "environment"] {
+            fs::create_dir_all(dir.join(name).join("nixpkgs")).unwrap();
+        }
+        let entry = |name: &str| format!("nixpkgs={}", dir.join(name).join("nixpkgs").display());
+        let nixpkgs = |nix_path: NixPath| nix_path.resolve("nixpkgs");
+        let path = |name: &str| Some(dir.join(name).join("nixpkgs"));
+
+        let previous = env::var_os("NIX_PATH");
+        env::set_var("NIX_PATH", entry("environment"));
+        let string = json!({ "nixPath": entry("setting") });
+        let array = json!({ "nixPath": ["channel:nixos-unstable", entry("array")] });
+        assert_eq!(nixpkgs(NixPath::new(Some(&string))), path("setting"));
+        assert_eq!(nixpkgs(NixPath::new(Some(&array))), path("array"));
+        assert_eq!(nixpkgs(NixPath::new(Some(&json!({})))), path("environment"));
+        assert_eq!(nixpkgs(NixPath::new(None)), path("environment"));
+        match previous {
+            Some(previous) => env::set_var("NIX_PATH", previous),
+            None => env::remove_var("NIX_PATH"),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
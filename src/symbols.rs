@@ -0,0 +1,219 @@
+use crate::utils;
+use lsp_types::{DocumentSymbol, Location, SymbolInformation, SymbolKind, Url};
+use rnix::{
+    types::{
+        EntryHolder, Ident, Inherit, KeyValue, Lambda, ParsedType, PatBind, TokenWrapper,
+        TypedNode, Wrapper,
+    },
+    SyntaxNode, TextRange,
+};
+use std::convert::TryFrom;
+
+/// A symbol in the outline, before ranges are converted to LSP positions
+struct Symbol {
+    name: String,
+    detail: Option<String>,
+    kind: SymbolKind,
+    range: TextRange,
+    selection: TextRange,
+    children: Vec<Symbol>,
+}
+impl Symbol {
+    fn into_lsp(self, code: &str) -> DocumentSymbol {
+        #[allow(deprecated)]
+        DocumentSymbol {
+            name: self.name,
+            detail: self.detail,
+            kind: self.kind,
+            tags: None,
+            deprecated: None,
+            range: utils::range(code, self.range),
+            selection_range: utils::range(code, self.selection),
+            children: Some(
+                self.children
+                    .into_iter()
+                    .map(|child| child.into_lsp(code))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Add a symbol, merging it with an earlier set of the same name so that
+/// `a.b = 1; a.c = 2;` shows up as a single `a`
+fn push(symbols: &mut Vec<Symbol>, symbol: Symbol) {
+    let existing = symbols.iter_mut().find(|existing| {
+        existing.name == symbol.name
+            && existing.kind == SymbolKind::Namespace
+            && symbol.kind == SymbolKind::Namespace
+    });
+    match existing {
+        Some(existing) => {
+            existing.range = existing.range.extend_to(&symbol.range);
+            for child in symbol.children {
+                push(&mut existing.children, child);
+            }
+        }
+        None => symbols.push(symbol),
+    }
+}
+
+fn kind_of(value: &SyntaxNode) -> SymbolKind {
+    match ParsedType::try_from(value.clone()) {
+        Ok(ParsedType::Lambda(_)) => SymbolKind::Function,
+        Ok(ParsedType::AttrSet(_)) => SymbolKind::Namespace,
+        Ok(ParsedType::Paren(paren)) => paren
+            .inner()
+            .map_or(SymbolKind::Variable, |inner| kind_of(&inner)),
+        _ => SymbolKind::Variable,
+    }
+}
+
+fn variable(ident: &Ident, range: TextRange, detail: Option<String>) -> Symbol {
+    Symbol {
+        name: ident.as_str().to_owned(),
+        detail,
+        kind: SymbolKind::Variable,
+        range,
+        selection: ident.node().text_range(),
+        children: Vec::new(),
+    }
+}
+
+fn entry_symbol(entry: &KeyValue) -> Option<Symbol> {
+    let key = entry.key()?;
+    let value = entry.value()?;
+    let mut path = key.path().collect::<Vec<_>>();
+
+    // Build the symbol from the innermost segment outwards
+    let last = path.pop()?;
+    let mut symbol = Symbol {
+        name: utils::key_name(&last).unwrap_or_else(|| last.text().to_string()),
+        detail: None,
+        kind: kind_of(&value),
+        range: entry.node().text_range(),
+        selection: last.text_range(),
+        children: symbols_in(&value),
+    };
+    for segment in path.into_iter().rev() {
+        symbol = Symbol {
+            name: utils::key_name(&segment).unwrap_or_else(|| segment.text().to_string()),
+            detail: None,
+            kind: SymbolKind::Namespace,
+            range: entry.node().text_range(),
+            selection: segment.text_range(),
+            children: vec![symbol],
+        };
+    }
+    Some(symbol)
+}
+
+fn inherit_symbols(inherit: &Inherit) -> Vec<Symbol> {
+    let detail = inherit
+        .from()
+        .and_then(|from| from.inner())
+        .map(|from| format!("inherited from {}", from.text()));
+    inherit
+        .idents()
+        .map(|ident| variable(&ident, inherit.node().text_range(), detail.clone()))
+        .collect()
+}
+
+fn holder_symbols<T: EntryHolder>(holder: &T) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+    for inherit in holder.inherits() {
+        for symbol in inherit_symbols(&inherit) {
+            push(&mut symbols, symbol);
+        }
+    }
+    for entry in holder.entries() {
+        if let Some(symbol) = entry_symbol(&entry) {
+            push(&mut symbols, symbol);
+        }
+    }
+    symbols.sort_by_key(|symbol| symbol.range.start());
+    symbols
+}
+
+fn lambda_symbols(lambda: &Lambda) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+    match lambda.arg().map(ParsedType::try_from) {
+        Some(Ok(ParsedType::Ident(ident))) => {
+            symbols.push(variable(&ident, ident.node().text_range(), None));
+        }
+        Some(Ok(ParsedType::Pattern(pattern))) => {
+            let bind = pattern.node().children().find_map(PatBind::cast);
+            if let Some(ident) = bind.and_then(|bind| bind.name()) {
+                symbols.push(variable(&ident, ident.node().text_range(), None));
+            }
+            for entry in pattern.entries() {
+                if let Some(ident) = entry.name() {
+                    symbols.push(variable(&ident, entry.node().text_range(), None));
+                }
+            }
+            symbols.sort_by_key(|symbol| symbol.range.start());
+        }
+        _ => (),
+    }
+    if let Some(body) = lambda.body() {
+        symbols.extend(symbols_in(&body));
+    }
+    symbols
+}
+
+/// Return the outline of everything bound within a node
+fn symbols_in(node: &SyntaxNode) -> Vec<Symbol> {
+    match ParsedType::try_from(node.clone()) {
+        Ok(ParsedType::AttrSet(set)) => holder_symbols(&set),
+        Ok(ParsedType::LegacyLet(let_)) => holder_symbols(&let_),
+        Ok(ParsedType::LetIn(let_in)) => {
+            let mut symbols = holder_symbols(&let_in);
+            if let Some(body) = let_in.body() {
+                symbols.extend(symbols_in(&body));
+            }
+            symbols
+        }
+        Ok(ParsedType::Lambda(lambda)) => lambda_symbols(&lambda),
+        _ => node
+            .children()
+            .flat_map(|child| symbols_in(&child))
+            .collect(),
+    }
+}
+
+/// Return the hierarchical outline of a file
+pub fn document_symbols(code: &str, root: &SyntaxNode) -> Vec<DocumentSymbol> {
+    symbols_in(root)
+        .into_iter()
+        .map(|symbol| symbol.into_lsp(code))
+        .collect()
+}
+
+/// Flatten an outline for clients which don't support nested symbols
+pub fn flatten(
+    uri: &Url,
+    symbols: Vec<DocumentSymbol>,
+    container: Option<&str>,
+    out: &mut Vec<SymbolInformation>,
+) {
+    for symbol in symbols {
+        let children = symbol.children.unwrap_or_default();
+        #[allow(deprecated)]
+        out.push(SymbolInformation {
+            name: symbol.name.clone(),
+            kind: symbol.kind,
+            tags: None,
+            deprecated: None,
+            location: Location {
+                uri: uri.clone(),
+                range: symbol.range,
+            },
+            container_name: container.map(String::from),
+        });
+        let path = match container {
+            Some(container) => format!("{}.{}", container, symbol.name),
+            None => symbol.name,
+        };
+        flatten(uri, children, Some(&path), out);
+    }
+}
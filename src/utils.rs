@@ -1,5 +1,11 @@
+use crate::nix_path::NixPath;
+use dirs::home_dir;
 use lsp_types::*;
-use rnix::{types::*, SyntaxNode, TextRange, TextUnit, TokenAtOffset};
+use rnix::{
+    types::*,
+    value::{Anchor, StrPart, Value as ParsedValue},
+    SyntaxKind, SyntaxNode, SyntaxToken, TextRange, TextUnit, TokenAtOffset,
+};
 use std::{collections::HashMap, convert::TryFrom, path::PathBuf, rc::Rc};
 
 pub fn uri_path(uri: &Url) -> Option<PathBuf> {
@@ -18,16 +24,30 @@ pub fn lookup_pos(code: &str, pos: Position) -> Option<usize> {
         offset += line.len() + 1;
     }
 
-    lines.next().and_then(|line| {
-        Some(
-            offset
-                + line
-                    .chars()
-                    .take(usize::try_from(pos.character).ok()?)
-                    .map(char::len_utf8)
-                    .sum::<usize>(),
-        )
-    })
+    let line = lines.next()?;
+    let mut utf16 = 0;
+    for c in line.chars() {
+        if utf16 >= pos.character {
+            break;
+        }
+        utf16 += c.len_utf16() as u64;
+        offset += c.len_utf8();
+    }
+    Some(offset)
+}
+pub fn apply_change(code: &mut String, change: TextDocumentContentChangeEvent) -> Option<()> {
+    match change.range {
+        Some(range) => {
+            let start = lookup_pos(code, range.start)?;
+            let end = lookup_pos(code, range.end)?;
+            if start > end {
+                return None;
+            }
+            code.replace_range(start..end, &change.text);
+        }
+        None => *code = change.text,
+    }
+    Some(())
 }
 pub fn offset_to_pos(code: &str, offset: usize) -> Position {
     let start_of_line = code[..offset].rfind('\n').map_or(0, |n| n + 1);
@@ -45,6 +65,38 @@ pub fn range(code: &str, range: TextRange) -> Range {
         end: offset_to_pos(code, range.end().to_usize()),
     }
 }
+/// The start of each line in a file, for converting many offsets without
+/// scanning the file from the start each time
+#[derive(Debug)]
+pub struct LineIndex<'a> {
+    pub code: &'a str,
+    starts: Vec<usize>,
+}
+impl<'a> LineIndex<'a> {
+    pub fn new(code: &'a str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(code.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        Self { code, starts }
+    }
+    pub fn position(&self, offset: usize) -> Position {
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.starts[line];
+        Position {
+            line: line as u64,
+            character: self.code[start..offset]
+                .chars()
+                .map(|c| c.len_utf16() as u64)
+                .sum(),
+        }
+    }
+    pub fn range(&self, range: TextRange) -> Range {
+        Range {
+            start: self.position(range.start().to_usize()),
+            end: self.position(range.end().to_usize()),
+        }
+    }
+}
 pub struct CursorInfo {
     pub path: Vec<String>,
     pub ident: Ident,
@@ -58,6 +110,33 @@ pub fn closest_node_to(root: &SyntaxNode, offset: usize) -> Option<SyntaxNode> {
     }
 }
 
+/// Return the path literal at the cursor
+pub fn path_at(root: &SyntaxNode, offset: usize) -> Option<SyntaxNode> {
+    let is_path = |token: &SyntaxToken| token.kind() == SyntaxKind::TOKEN_PATH;
+    let token = match root.token_at_offset(TextUnit::from_usize(offset)) {
+        TokenAtOffset::None => None,
+        TokenAtOffset::Single(token) => Some(token).filter(is_path),
+        TokenAtOffset::Between(left, right) => Some(left)
+            .filter(is_path)
+            .or_else(|| Some(right).filter(is_path)),
+    }?;
+    Some(token.parent())
+}
+/// Return the attribute selection the cursor is right after the dot of,
+/// like `a.` before anything else is typed
+pub fn select_before(root: &SyntaxNode, offset: usize) -> Option<Select> {
+    let token = match root.token_at_offset(TextUnit::from_usize(offset)) {
+        TokenAtOffset::None => None,
+        TokenAtOffset::Single(token) => Some(token),
+        TokenAtOffset::Between(left, _) => Some(left),
+    }?;
+    if token.kind() != SyntaxKind::TOKEN_DOT
+        || token.text_range().end() != TextUnit::from_usize(offset)
+    {
+        return None;
+    }
+    Select::cast(token.parent())
+}
 pub fn ident_at(root: &SyntaxNode, offset: usize) -> Option<CursorInfo> {
     let ident = match root.token_at_offset(TextUnit::from_usize(offset)) {
         TokenAtOffset::None => None,
@@ -66,6 +145,29 @@ pub fn ident_at(root: &SyntaxNode, offset: usize) -> Option<CursorInfo> {
             Ident::cast(left.parent()).or_else(|| Ident::cast(right.parent()))
         }
     }?;
+    cursor_info(ident)
+}
+/// Returns true if the cursor is on the syntax of the file's root expression
+/// itself, like the braces of a set or the argument pattern of a function,
+/// rather than on anything within it
+pub fn is_on_root(root: &SyntaxNode, offset: usize) -> bool {
+    let Some(expr) = Root::cast(root.clone()).and_then(|root| root.inner()) else {
+        return false;
+    };
+    let pattern = Lambda::cast(expr.clone())
+        .and_then(|lambda| lambda.arg())
+        .filter(|arg| arg.kind() == SyntaxKind::NODE_PATTERN);
+    let on_root = |token: SyntaxToken| {
+        token.kind() != SyntaxKind::TOKEN_WHITESPACE
+            && (token.parent() == expr || Some(token.parent()) == pattern)
+    };
+    match root.token_at_offset(TextUnit::from_usize(offset)) {
+        TokenAtOffset::None => false,
+        TokenAtOffset::Single(token) => on_root(token),
+        TokenAtOffset::Between(left, right) => on_root(left) || on_root(right),
+    }
+}
+pub fn cursor_info(ident: Ident) -> Option<CursorInfo> {
     let parent = ident.node().parent();
     if let Some(attr) = parent.clone().and_then(Key::cast) {
         let mut path = Vec::new();
@@ -100,18 +202,243 @@ pub fn ident_at(root: &SyntaxNode, offset: usize) -> Option<CursorInfo> {
     }
 }
 
-#[derive(Debug)]
+/// Returns true if the identifier refers to a variable, as opposed to
+/// naming an attribute or binding a new variable
+pub fn is_reference(ident: &Ident) -> bool {
+    let node = ident.node();
+    let Some(parent) = node.parent() else {
+        return true;
+    };
+    if checked_attr(ident).is_some() {
+        return false;
+    }
+    match parent.kind() {
+        SyntaxKind::NODE_KEY | SyntaxKind::NODE_PAT_BIND => false,
+        SyntaxKind::NODE_SELECT => {
+            Select::cast(parent)
+                .and_then(|select| select.index())
+                .as_ref()
+                != Some(node)
+        }
+        SyntaxKind::NODE_INHERIT => {
+            Inherit::cast(parent).is_some_and(|inherit| inherit.from().is_none())
+        }
+        SyntaxKind::NODE_PAT_ENTRY => PatEntry::cast(parent)
+            .and_then(|entry| entry.name())
+            .is_none_or(|name| name.node() != node),
+        SyntaxKind::NODE_LAMBDA => {
+            Lambda::cast(parent)
+                .and_then(|lambda| lambda.arg())
+                .as_ref()
+                != Some(node)
+        }
+        _ => true,
+    }
+}
+
+/// If the identifier is part of the attribute path checked by `set ? a.b`,
+/// return the set along with the segments of the path before the
+/// identifier
+pub fn checked_attr(ident: &Ident) -> Option<(SyntaxNode, Vec<SyntaxNode>)> {
+    let mut path = ident.node().clone();
+    while let Some(select) = path.parent().and_then(Select::cast) {
+        path = select.node().clone();
+    }
+    let op = path.parent().and_then(BinOp::cast)?;
+    if op.operator() != BinOpKind::IsSet || op.rhs().as_ref() != Some(&path) {
+        return None;
+    }
+    let mut segments = Vec::new();
+    while let Some(select) = Select::cast(path.clone()) {
+        segments.push(select.index()?);
+        path = select.set()?;
+    }
+    segments.push(path);
+    segments.reverse();
+    let position = segments
+        .iter()
+        .position(|segment| segment == ident.node())?;
+    segments.truncate(position);
+    Some((op.lhs()?, segments))
+}
+
+/// Return the node from which to look up the scope of a variable
+/// reference. Identifiers in `inherit x;` refer to the scope surrounding
+/// the set or let-block, not the bindings made by it.
+pub fn reference_scope(ident: &Ident) -> Option<SyntaxNode> {
+    let parent = ident.node().parent()?;
+    if Inherit::cast(parent.clone()).is_some() {
+        parent.parent()?.parent()
+    } else {
+        Some(ident.node().clone())
+    }
+}
+
+/// Return the edits renaming an identifier. Since `inherit x;` both reads
+/// and binds `x`, the inherited identifier is split out into `x = y;` so
+/// that only the side being renamed changes.
+pub fn rename_ident(code: &str, ident: &Ident, declaration: bool, new_name: &str) -> Vec<TextEdit> {
+    let node = ident.node();
+    let Some(inherit) = node.parent().and_then(Inherit::cast) else {
+        return vec![TextEdit {
+            range: range(code, node.text_range()),
+            new_text: new_name.to_owned(),
+        }];
+    };
+    let old = ident.as_str();
+    let (key, mut value) = if declaration {
+        (new_name.to_owned(), old.to_owned())
+    } else {
+        (old.to_owned(), new_name.to_owned())
+    };
+    if let Some(from) = inherit.from() {
+        value = format!("{}.{}", from.node().text(), value);
+    }
+    let binding = format!("{key} = {value};");
+
+    if inherit.idents().count() == 1 {
+        return vec![TextEdit {
+            range: range(code, inherit.node().text_range()),
+            new_text: binding,
+        }];
+    }
+    // Remove the identifier along with its leading whitespace and add the
+    // binding right after the inherit
+    let start = match node.prev_sibling_or_token() {
+        Some(prev) if prev.kind() == SyntaxKind::TOKEN_WHITESPACE => prev.text_range().start(),
+        _ => node.text_range().start(),
+    };
+    let end = inherit.node().text_range().end();
+    vec![
+        TextEdit {
+            range: range(code, TextRange::from_to(start, node.text_range().end())),
+            new_text: String::new(),
+        },
+        TextEdit {
+            range: range(code, TextRange::from_to(end, end)),
+            new_text: format!(" {binding}"),
+        },
+    ]
+}
+
+/// Resolve a path literal written in a file
+pub fn resolve_path(nix_path: &NixPath, file: &Url, anchor: &Anchor, path: &str) -> Option<Url> {
+    match anchor {
+        Anchor::Absolute => Url::from_file_path(path).ok(),
+        Anchor::Relative => file.join(path).ok(),
+        Anchor::Home => Url::from_directory_path(home_dir()?).ok()?.join(path).ok(),
+        Anchor::Store => Url::from_file_path(nix_path.resolve(path)?).ok(),
+    }
+}
+/// Resolve the file a path expression imports, which is `default.nix` for
+/// directories
+pub fn path_target(nix_path: &NixPath, file: &Url, node: &SyntaxNode) -> Option<Url> {
+    let ParsedValue::Path(anchor, path) = Value::cast(node.clone())?.to_value().ok()? else {
+        return None;
+    };
+    let mut target = resolve_path(nix_path, file, &anchor, &path)?;
+    if uri_path(&target)?.is_dir() {
+        target
+            .path_segments_mut()
+            .ok()?
+            .pop_if_empty()
+            .push("default.nix");
+    }
+    Some(target)
+}
+/// Return the file a simple `import <path>` expression refers to
+pub fn import_target(nix_path: &NixPath, file: &Url, apply: &Apply) -> Option<Url> {
+    let lambda = Ident::cast(apply.lambda()?)?;
+    if lambda.as_str() != "import" {
+        return None;
+    }
+    path_target(nix_path, file, &apply.value()?)
+}
+/// Return the file a `callPackage <path>` expression refers to, with or
+/// without a set like `pkgs.callPackage`
+pub fn call_package_target(nix_path: &NixPath, file: &Url, apply: &Apply) -> Option<Url> {
+    let lambda = apply.lambda()?;
+    let function = match Select::cast(lambda.clone()) {
+        Some(select) => Ident::cast(select.index()?)?,
+        None => Ident::cast(lambda)?,
+    };
+    if function.as_str() != "callPackage" {
+        return None;
+    }
+    path_target(nix_path, file, &apply.value()?)
+}
+/// Return all files imported or passed to `callPackage` anywhere within a
+/// node
+pub fn imports(nix_path: &NixPath, file: &Url, root: &SyntaxNode) -> Vec<Url> {
+    root.descendants()
+        .filter_map(Apply::cast)
+        .filter_map(|apply| {
+            import_target(nix_path, file, &apply)
+                .or_else(|| call_package_target(nix_path, file, &apply))
+        })
+        .collect()
+}
+
+/// Returns true if the name can be used as a variable without quoting
+pub fn is_valid_ident(name: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+    ];
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'))
+        && !KEYWORDS.contains(&name)
+}
+/// Return the name of an attribute path segment, if it's statically known
+pub fn key_name(node: &SyntaxNode) -> Option<String> {
+    if let Some(ident) = Ident::cast(node.clone()) {
+        return Some(ident.as_str().into());
+    }
+    let mut name = String::new();
+    for part in Str::cast(node.clone())?.parts() {
+        match part {
+            StrPart::Literal(literal) => name.push_str(&literal),
+            StrPart::Ast(_) => return None,
+        }
+    }
+    Some(name)
+}
+
+#[derive(Clone, Debug)]
 pub struct Var {
     pub file: Rc<Url>,
     pub set: SyntaxNode,
     pub key: SyntaxNode,
     pub value: Option<SyntaxNode>,
 }
+impl Var {
+    /// Returns true if both variables come from the same binding
+    pub fn is_same(&self, other: &Var) -> bool {
+        self.file == other.file && self.key.text_range() == other.key.text_range()
+    }
+}
 pub fn populate<T: EntryHolder>(
     file: &Rc<Url>,
     scope: &mut HashMap<String, Var>,
     set: &T,
 ) -> Option<()> {
+    for inherit in set.inherits() {
+        for ident in inherit.idents() {
+            if !scope.contains_key(ident.as_str()) {
+                scope.insert(
+                    ident.as_str().into(),
+                    Var {
+                        file: Rc::clone(file),
+                        set: set.node().to_owned(),
+                        key: ident.node().to_owned(),
+                        value: None,
+                    },
+                );
+            }
+        }
+    }
     for entry in set.entries() {
         let attr = entry.key()?;
         let mut path = attr.path();
@@ -131,61 +458,178 @@ pub fn populate<T: EntryHolder>(
     }
     Some(())
 }
-pub fn scope_for(file: &Rc<Url>, node: SyntaxNode) -> Option<HashMap<String, Var>> {
+/// Return the bindings made directly by a set, let-block or function
+pub fn entries_of(file: &Rc<Url>, holder: &SyntaxNode) -> Option<HashMap<String, Var>> {
+    let mut scope = HashMap::new();
+    match ParsedType::try_from(holder.clone()).ok()? {
+        ParsedType::AttrSet(set) => populate(file, &mut scope, &set),
+        ParsedType::LetIn(let_in) => populate(file, &mut scope, &let_in),
+        ParsedType::LegacyLet(let_) => populate(file, &mut scope, &let_),
+        ParsedType::Lambda(lambda) => {
+            let mut args = Vec::new();
+            match ParsedType::try_from(lambda.arg()?).ok()? {
+                ParsedType::Ident(ident) => args.push(ident),
+                ParsedType::Pattern(pattern) => {
+                    let bind = pattern.node().children().find_map(PatBind::cast);
+                    args.extend(bind.and_then(|bind| bind.name()));
+                    args.extend(pattern.entries().filter_map(|entry| entry.name()));
+                }
+                _ => (),
+            }
+            for ident in args {
+                scope.entry(ident.as_str().into()).or_insert_with(|| Var {
+                    file: Rc::clone(file),
+                    set: lambda.node().to_owned(),
+                    key: ident.node().to_owned(),
+                    value: None,
+                });
+            }
+            Some(())
+        }
+        _ => return None,
+    };
+    Some(scope)
+}
+/// Returns true if bindings made by the node are visible to its children
+pub fn is_scope(node: &SyntaxNode) -> bool {
+    match ParsedType::try_from(node.clone()) {
+        Ok(ParsedType::AttrSet(set)) => set.recursive(),
+        Ok(ParsedType::LetIn(_) | ParsedType::LegacyLet(_) | ParsedType::Lambda(_)) => true,
+        _ => false,
+    }
+}
+pub fn scope_for(file: &Rc<Url>, node: SyntaxNode) -> HashMap<String, Var> {
     let mut scope = HashMap::new();
 
     let mut current = Some(node);
     while let Some(node) = current {
-        match ParsedType::try_from(node.clone()) {
-            Ok(ParsedType::LetIn(let_in)) => {
-                populate(&file, &mut scope, &let_in);
+        if is_scope(&node) {
+            for (name, var) in entries_of(file, &node).into_iter().flatten() {
+                scope.entry(name).or_insert(var);
             }
-            Ok(ParsedType::LegacyLet(let_)) => {
-                populate(&file, &mut scope, &let_);
+        }
+        current = node.parent();
+    }
+
+    scope
+}
+/// Return the `with` expressions whose body contains the node, innermost
+/// first
+pub fn enclosing_withs(node: &SyntaxNode) -> Vec<With> {
+    node.ancestors()
+        .zip(node.ancestors().skip(1))
+        .filter_map(|(child, parent)| With::cast(parent).filter(|with| with.body() == Some(child)))
+        .collect()
+}
+/// Return the dotted path of a variable or attribute selection, like
+/// `pkgs.lib`
+pub fn dotted_path(node: &SyntaxNode) -> Option<Vec<String>> {
+    match ParsedType::try_from(node.clone()).ok()? {
+        ParsedType::Ident(ident) => Some(vec![ident.as_str().to_owned()]),
+        ParsedType::Select(select) => {
+            let mut path = dotted_path(&select.set()?)?;
+            path.push(Ident::cast(select.index()?)?.as_str().to_owned());
+            Some(path)
+        }
+        ParsedType::Paren(paren) => dotted_path(&paren.inner()?),
+        _ => None,
+    }
+}
+/// Return the documented names a variable like `mkIf` or
+/// `python3Packages.foo` could refer to through `with pkgs;` or `with lib;`
+/// around the node, innermost first, along with the length of the prefix
+/// added by the `with`. Documented packages are named without `pkgs.`, so
+/// `with pkgs;` adds nothing. Variables and `with` targets which are bound
+/// in the code are left to be resolved statically.
+pub fn with_names(file: &Rc<Url>, node: &SyntaxNode, path: &[String]) -> Vec<(Vec<String>, usize)> {
+    let bound = |node: SyntaxNode, name: &str| scope_for(file, node).contains_key(name);
+    if path.is_empty() || bound(node.clone(), &path[0]) {
+        return Vec::new();
+    }
+    enclosing_withs(node)
+        .into_iter()
+        .filter_map(|with| {
+            let namespace = with.namespace()?;
+            let mut prefix = dotted_path(&namespace)?;
+            if bound(namespace, &prefix[0]) {
+                return None;
             }
-            Ok(ParsedType::AttrSet(set)) => {
-                if set.recursive() {
-                    populate(&file, &mut scope, &set);
+            match prefix[0].as_str() {
+                "pkgs" => {
+                    prefix.remove(0);
                 }
+                "lib" => (),
+                _ => return None,
             }
-            Ok(ParsedType::Lambda(lambda)) => match ParsedType::try_from(lambda.arg()?) {
-                Ok(ParsedType::Ident(ident)) => {
-                    if !scope.contains_key(ident.as_str()) {
-                        scope.insert(
-                            ident.as_str().into(),
-                            Var {
-                                file: Rc::clone(&file),
-                                set: lambda.node().clone(),
-                                key: ident.node().clone(),
-                                value: None,
-                            },
-                        );
-                    }
-                }
-                Ok(ParsedType::Pattern(pattern)) => {
-                    for entry in pattern.entries() {
-                        let ident = entry.name()?;
-                        if !scope.contains_key(ident.as_str()) {
-                            scope.insert(
-                                ident.as_str().into(),
-                                Var {
-                                    file: Rc::clone(&file),
-                                    set: lambda.node().to_owned(),
-                                    key: ident.node().to_owned(),
-                                    value: None,
-                                },
-                            );
-                        }
-                    }
-                }
-                _ => (),
-            },
-            _ => (),
+            let len = prefix.len();
+            prefix.extend_from_slice(path);
+            Some((prefix, len))
+        })
+        .collect()
+}
+/// Make sure renaming the binding to the new name doesn't change what
+/// any identifier refers to
+pub fn check_capture(target: &Var, usages: &[(Url, Ident)], new_name: &str) -> Result<(), String> {
+    let inside = |node: &SyntaxNode, scope: &SyntaxNode| {
+        node != scope && node.text_range().is_subrange(&scope.text_range())
+    };
+    let siblings = entries_of(&target.file, &target.set).unwrap_or_default();
+    if siblings.contains_key(new_name) {
+        return Err(format!("`{new_name}` is already defined here"));
+    }
+
+    // Usages must not resolve to an inner binding of the new name
+    for (uri, ident) in usages {
+        if !is_reference(ident) {
+            continue;
+        }
+        let file = Rc::new(uri.clone());
+        let scope = reference_scope(ident)
+            .map(|node| scope_for(&file, node))
+            .unwrap_or_default();
+        if scope
+            .get(new_name)
+            .is_some_and(|var| inside(&var.set, &target.set))
+        {
+            return Err(format!(
+                "`{new_name}` would be captured by an inner binding of the same name"
+            ));
+        }
+        // Usages found through `with` lose to any variable of the new name
+        let lexical = scope
+            .get(ident.as_str())
+            .is_some_and(|var| var.is_same(target));
+        if !lexical && scope.contains_key(new_name) {
+            return Err(format!(
+                "`{new_name}` is already defined where `{}` comes from a `with`",
+                ident.as_str()
+            ));
         }
-        current = node.parent();
     }
 
-    Some(scope)
+    // Nothing within scope of the binding may refer to an outer variable
+    // of the new name, since the renamed binding would shadow it
+    if !is_scope(&target.set) {
+        return Ok(());
+    }
+    let descendants = target.set.descendants().filter_map(Ident::cast);
+    for ident in descendants.filter(|ident| ident.as_str() == new_name) {
+        if !is_reference(&ident) {
+            continue;
+        }
+        let scope = reference_scope(&ident)
+            .map(|node| scope_for(&target.file, node))
+            .unwrap_or_default();
+        if scope
+            .get(new_name)
+            .is_none_or(|var| inside(&target.set, &var.set))
+        {
+            return Err(format!(
+                "`{new_name}` is already used where the binding is visible"
+            ));
+        }
+    }
+    Ok(())
 }
 pub fn selection_ranges(root: &SyntaxNode, content: &str, pos: Position) -> Option<SelectionRange> {
     let pos = lookup_pos(content, pos)?;
@@ -215,3 +659,190 @@ pub fn selection_ranges(root: &SyntaxNode, content: &str, pos: Position) -> Opti
 
     root.map(|b| *b)
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::{env, fs, path::Path, process};
+
+    /// Create an empty directory for a test, unique to the process
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rnix-lsp-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+    fn touch(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "{}").unwrap();
+    }
+    /// Resolve the path expression `code` as if it was written in `file`
+    fn target(nix_path: &NixPath, file: &Url, code: &str) -> Option<Url> {
+        let ast = rnix::parse(code);
+        let root = Root::cast(ast.node())?;
+        path_target(nix_path, file, &root.inner()?)
+    }
+
+    fn pos(line: u64, character: u64) -> Position {
+        Position { line, character }
+    }
+    fn change(start: Position, end: Position, text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: Some(Range { start, end }),
+            range_length: None,
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn lookup_pos_counts_utf16() {
+        let code = "a😀b\néc";
+        assert_eq!(lookup_pos(code, pos(0, 0)), Some(0));
+        // The emoji is a surrogate pair, so it takes two UTF-16 units
+        assert_eq!(lookup_pos(code, pos(0, 3)), code.find('b'));
+        assert_eq!(lookup_pos(code, pos(1, 1)), code.find('c'));
+    }
+    #[test]
+    fn lookup_pos_clamps_to_line() {
+        let code = "abc\ndef";
+        assert_eq!(lookup_pos(code, pos(0, 100)), Some(3));
+        assert_eq!(lookup_pos(code, pos(1, 100)), Some(code.len()));
+        assert_eq!(lookup_pos(code, pos(2, 0)), None);
+    }
+    #[test]
+    fn apply_changes_in_order() {
+        let mut code = String::from("hello\nworld");
+        let changes = vec![
+            change(pos(0, 0), pos(0, 5), "hi"),
+            // Positions refer to the text after the previous change
+            change(pos(1, 0), pos(1, 5), "there"),
+            change(pos(0, 2), pos(0, 2), "!"),
+            change(pos(0, 3), pos(1, 0), " "),
+        ];
+        for change in changes {
+            assert_eq!(apply_change(&mut code, change), Some(()));
+        }
+        assert_eq!(code, "hi! there");
+
+        let full = TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: String::from("é"),
+        };
+        assert_eq!(apply_change(&mut code, full), Some(()));
+        assert_eq!(code, "é");
+    }
+    #[test]
+    fn apply_change_after_multibyte() {
+        let mut code = String::from("😀é = 1;");
+        let change = change(pos(0, 3), pos(0, 3), "x");
+        assert_eq!(apply_change(&mut code, change), Some(()));
+        assert_eq!(code, "😀éx = 1;");
+    }
+    #[test]
+    fn apply_change_missing_line() {
+        let mut code = String::from("a\nb");
+        let change = change(pos(1, 0), pos(3, 0), "c");
+        assert_eq!(apply_change(&mut code, change), None);
+        assert_eq!(code, "a\nb");
+    }
+    #[test]
+    fn checked_attrs_are_not_references() {
+        let ast = rnix::parse("s ? a.b || t ? c");
+        let idents = ast
+            .node()
+            .descendants()
+            .filter_map(Ident::cast)
+            .map(|ident| {
+                let path = checked_attr(&ident).map(|(set, path)| {
+                    let path = path.iter().map(ToString::to_string);
+                    (set.to_string(), path.collect::<Vec<_>>())
+                });
+                (ident.as_str().to_owned(), is_reference(&ident), path)
+            })
+            .collect::<Vec<_>>();
+        let checked = |set: &str, path: &[&str]| {
+            let path = path.iter().map(ToString::to_string).collect();
+            Some((set.to_owned(), path))
+        };
+        assert_eq!(
+            idents,
+            vec![
+                ("s".into(), true, None),
+                ("a".into(), false, checked("s", &[])),
+                ("b".into(), false, checked("s", &["a"])),
+                ("t".into(), true, None),
+                ("c".into(), false, checked("t", &[])),
+            ]
+        );
+    }
+    #[test]
+    fn line_index_matches_offset_to_pos() {
+        let code = "let\n  a😀 = \"é\";\n\n  b = a;\nin b\n";
+        let lines = LineIndex::new(code);
+        for (offset, _) in code.char_indices().chain(Some((code.len(), ' '))) {
+            assert_eq!(lines.position(offset), offset_to_pos(code, offset));
+        }
+    }
+    #[test]
+    fn resolve_path_anchors() {
+        let file = Url::parse("file:///src/dir/file.nix").unwrap();
+        let nix_path = NixPath::default();
+        let resolve = |anchor, path| resolve_path(&nix_path, &file, &anchor, path);
+        assert_eq!(
+            resolve(Anchor::Relative, "./other.nix"),
+            Url::parse("file:///src/dir/other.nix").ok()
+        );
+        assert_eq!(
+            resolve(Anchor::Relative, "../up.nix"),
+            Url::parse("file:///src/up.nix").ok()
+        );
+        assert_eq!(
+            resolve(Anchor::Absolute, "/etc/nixos/configuration.nix"),
+            Url::parse("file:///etc/nixos/configuration.nix").ok()
+        );
+        assert_eq!(
+            resolve(Anchor::Home, "config.nix"),
+            Url::from_file_path(home_dir().unwrap().join("config.nix")).ok()
+        );
+        // Nothing to search
+        assert_eq!(resolve(Anchor::Store, "nixpkgs"), None);
+    }
+    #[test]
+    fn path_target_default_nix() {
+        let dir = temp_dir("path-target");
+        touch(&dir.join("pkgs/hello/default.nix"));
+        touch(&dir.join("pkgs/hello/builder.nix"));
+        touch(&dir.join("channels/nixpkgs/lib/default.nix"));
+        let file = Url::from_file_path(dir.join("pkgs/all.nix")).unwrap();
+        let nix_path = NixPath::parse(&dir.join("channels").display().to_string());
+        let url = |path: &str| Url::from_file_path(dir.join(path)).ok();
+
+        assert_eq!(
+            target(&nix_path, &file, "./hello"),
+            url("pkgs/hello/default.nix")
+        );
+        assert_eq!(
+            target(&nix_path, &file, "./hello/builder.nix"),
+            url("pkgs/hello/builder.nix")
+        );
+        let absolute = dir.join("pkgs/hello").display().to_string();
+        assert_eq!(
+            target(&nix_path, &file, &absolute),
+            url("pkgs/hello/default.nix")
+        );
+        assert_eq!(
+            target(&nix_path, &file, "<nixpkgs/lib>"),
+            url("channels/nixpkgs/lib/default.nix")
+        );
+        assert_eq!(target(&nix_path, &file, "<nixpkgs/missing>"), None);
+        // Files that don't exist are still resolved, they might be created
+        // later
+        assert_eq!(
+            target(&nix_path, &file, "./missing.nix"),
+            url("pkgs/missing.nix")
+        );
+        assert_eq!(target(&nix_path, &file, "\"./hello\""), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
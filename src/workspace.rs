@@ -0,0 +1,353 @@
+use crate::{ext::GraphFormat, nix_path::NixPath, symbols, utils};
+use crossbeam_channel::Receiver;
+use lsp_types::{InitializeParams, SymbolInformation, Url};
+use rnix::{
+    types::{AttrSet, EntryHolder, ParsedType, TokenWrapper, TypedNode, Wrapper},
+    SyntaxNode,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    thread,
+};
+use walkdir::WalkDir;
+
+/// Maximum number of results to return from a symbol search. Clients query
+/// again as the user types, so there's no need to send everything.
+const MAX_SYMBOLS: usize = 100;
+/// Extra score for symbols named exactly like the query
+const EXACT_BONUS: usize = 10;
+
+/// What the indexer knows about a single file
+#[derive(Debug, Default, Serialize)]
+pub struct FileIndex {
+    #[serde(skip)]
+    /// The outline of the file, flattened for symbol search
+    pub symbols: Vec<SymbolInformation>,
+    /// Names bound at the top level of the file, after any function
+    /// arguments
+    pub bindings: Vec<String>,
+    /// Files which are imported or passed to `callPackage`
+    pub imports: Vec<Url>,
+    /// Dotted paths of all attributes defined in sets
+    pub attributes: Vec<String>,
+}
+impl FileIndex {
+    pub fn new(nix_path: &NixPath, uri: &Url, code: &str, root: &SyntaxNode) -> Self {
+        let mut symbols = Vec::new();
+        symbols::flatten(
+            uri,
+            symbols::document_symbols(code, root),
+            None,
+            &mut symbols,
+        );
+        let mut attributes = Vec::new();
+        attribute_paths(root, &mut Vec::new(), &mut attributes);
+        Self {
+            symbols,
+            bindings: top_level_bindings(root),
+            imports: utils::imports(nix_path, uri, root),
+            attributes,
+        }
+    }
+}
+
+/// Return the names bound by the outermost let-block or set of a file
+fn top_level_bindings(root: &SyntaxNode) -> Vec<String> {
+    fn bind<T: EntryHolder>(holder: &T, bindings: &mut Vec<String>) {
+        let inherited = holder.inherits().flat_map(|inherit| inherit.idents());
+        bindings.extend(inherited.map(|ident| ident.as_str().to_owned()));
+        let keys = holder
+            .entries()
+            .filter_map(|entry| entry.key()?.path().next());
+        bindings.extend(keys.filter_map(|key| utils::key_name(&key)));
+    }
+    let mut bindings = Vec::new();
+    let mut node = Some(root.clone());
+    while let Some(current) = node.take() {
+        node = match ParsedType::try_from(current) {
+            Ok(ParsedType::Root(root)) => root.inner(),
+            Ok(ParsedType::Paren(paren)) => paren.inner(),
+            Ok(ParsedType::Lambda(lambda)) => lambda.body(),
+            Ok(ParsedType::LetIn(let_in)) => {
+                bind(&let_in, &mut bindings);
+                let_in.body()
+            }
+            Ok(ParsedType::AttrSet(set)) => {
+                bind(&set, &mut bindings);
+                None
+            }
+            _ => None,
+        };
+    }
+    bindings
+}
+
+/// Collect the dotted path of every attribute defined in a set, including
+/// the attributes of nested sets
+fn attribute_paths(node: &SyntaxNode, prefix: &mut Vec<String>, out: &mut Vec<String>) {
+    let Some(set) = AttrSet::cast(node.clone()) else {
+        for child in node.children() {
+            attribute_paths(&child, &mut Vec::new(), out);
+        }
+        return;
+    };
+    for inherit in set.inherits() {
+        for ident in inherit.idents() {
+            prefix.push(ident.as_str().to_owned());
+            out.push(prefix.join("."));
+            prefix.pop();
+        }
+    }
+    for entry in set.entries() {
+        let path = entry.key().map(|key| {
+            key.path()
+                .map(|segment| utils::key_name(&segment))
+                .collect::<Option<Vec<_>>>()
+        });
+        let (Some(Some(path)), Some(value)) = (path, entry.value()) else {
+            continue;
+        };
+        let len = prefix.len();
+        prefix.extend(path);
+        out.push(prefix.join("."));
+        attribute_paths(&value, prefix, out);
+        prefix.truncate(len);
+    }
+}
+
+/// Progress of the background indexer
+pub enum Event {
+    /// Indexing started, with the number of files to index
+    Started(usize),
+    /// A file was read from disk and indexed
+    Indexed {
+        uri: Url,
+        index: FileIndex,
+        done: usize,
+        total: usize,
+    },
+    Finished,
+}
+
+/// Return all Nix files within the folders, skipping hidden files and
+/// directories such as `.git`
+fn nix_files(roots: &[PathBuf]) -> Vec<PathBuf> {
+    let is_hidden = |path: &Path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('.'))
+    };
+    roots
+        .iter()
+        .flat_map(|root| {
+            WalkDir::new(root)
+                .into_iter()
+                .filter_entry(move |entry| entry.path() == root || !is_hidden(entry.path()))
+                .filter_map(Result::ok)
+        })
+        .filter(|entry| entry.file_type().is_file())
+        .map(walkdir::DirEntry::into_path)
+        .filter(|path| path.extension().is_some_and(|ext| ext == "nix"))
+        .collect()
+}
+
+/// Index of every Nix file under the workspace folders
+#[derive(Default)]
+pub struct Workspace {
+    roots: Vec<PathBuf>,
+    files: HashMap<Url, FileIndex>,
+    /// Where to find `<...>` paths
+    pub nix_path: NixPath,
+}
+impl Workspace {
+    pub fn new(params: &InitializeParams) -> Self {
+        let roots = match (&params.workspace_folders, &params.root_uri) {
+            (Some(folders), _) => folders
+                .iter()
+                .filter_map(|folder| utils::uri_path(&folder.uri))
+                .collect(),
+            (None, Some(uri)) => utils::uri_path(uri).into_iter().collect(),
+            (None, None) => Vec::new(),
+        };
+        Self {
+            roots,
+            nix_path: NixPath::new(params.initialization_options.as_ref()),
+            ..Self::default()
+        }
+    }
+    /// Returns true if the file is within one of the workspace folders
+    pub fn contains(&self, uri: &Url) -> bool {
+        utils::uri_path(uri)
+            .is_some_and(|path| self.roots.iter().any(|root| path.starts_with(root)))
+    }
+    /// Read and index every file in the workspace on a separate thread. The
+    /// results are sent back over the returned channel and need to be
+    /// passed to `insert`.
+    pub fn spawn_indexer(&self) -> Receiver<Event> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let roots = self.roots.clone();
+        let nix_path = self.nix_path.clone();
+        thread::spawn(move || {
+            let files = nix_files(&roots);
+            let total = files.len();
+            if sender.send(Event::Started(total)).is_err() {
+                return;
+            }
+            for (done, path) in files.into_iter().enumerate() {
+                let (Ok(uri), Ok(code)) = (Url::from_file_path(&path), fs::read_to_string(&path))
+                else {
+                    continue;
+                };
+                let index = FileIndex::new(&nix_path, &uri, &code, &rnix::parse(&code).node());
+                let event = Event::Indexed {
+                    uri,
+                    index,
+                    done: done + 1,
+                    total,
+                };
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+            let _ = sender.send(Event::Finished);
+        });
+        receiver
+    }
+    pub fn insert(&mut self, uri: Url, index: FileIndex) {
+        if self.contains(&uri) {
+            self.files.insert(uri, index);
+        }
+    }
+    /// Index the symbols of a file, replacing what was known about it
+    pub fn index(&mut self, uri: &Url, code: &str, root: &SyntaxNode) {
+        if self.contains(uri) {
+            self.files
+                .insert(uri.clone(), FileIndex::new(&self.nix_path, uri, code, root));
+        }
+    }
+    /// Index the contents of a file on disk, or forget it if it can't be read
+    pub fn index_disk(&mut self, uri: &Url) {
+        match utils::uri_path(uri).and_then(|path| fs::read_to_string(path).ok()) {
+            Some(code) => self.index(uri, &code, &rnix::parse(&code).node()),
+            None => self.remove(uri),
+        }
+    }
+    pub fn remove(&mut self, uri: &Url) {
+        self.files.remove(uri);
+    }
+    /// Dump the import graph, along with what's known about each file
+    pub fn import_graph(&self, format: GraphFormat) -> serde_json::Value {
+        let mut files = self.files.iter().collect::<Vec<_>>();
+        files.sort_by_key(|(uri, _)| uri.as_str());
+        match format {
+            GraphFormat::Json => {
+                let files = files
+                    .into_iter()
+                    .map(|(uri, index)| (uri.to_string(), index))
+                    .collect::<BTreeMap<_, _>>();
+                serde_json::json!({ "files": files })
+            }
+            GraphFormat::Dot => {
+                let mut dot = String::from("digraph imports {\n");
+                for (uri, index) in files {
+                    let _ = writeln!(dot, "  {:?};", uri.path());
+                    for import in &index.imports {
+                        let _ = writeln!(dot, "  {:?} -> {:?};", uri.path(), import.path());
+                    }
+                }
+                dot.push('}');
+                serde_json::Value::String(dot)
+            }
+        }
+    }
+    /// Return all indexed files which import the given file
+    pub fn importers(&self, uri: &Url) -> Vec<Url> {
+        self.files
+            .iter()
+            .filter(|(_, index)| index.imports.contains(uri))
+            .map(|(importer, _)| importer.clone())
+            .collect()
+    }
+    /// Find the symbols whose dotted path fuzzily matches the query, best
+    /// matches first
+    pub fn search(&self, query: &str) -> Vec<SymbolInformation> {
+        let mut matches = self
+            .files
+            .values()
+            .flat_map(|index| &index.symbols)
+            .filter_map(|symbol| {
+                let path = match &symbol.container_name {
+                    Some(container) => format!("{}.{}", container, symbol.name),
+                    None => symbol.name.clone(),
+                };
+                let mut score = fuzzy_score(query, &path)?;
+                if symbol.name.eq_ignore_ascii_case(query) {
+                    score += EXACT_BONUS;
+                }
+                Some((score, path, symbol))
+            })
+            .collect::<Vec<_>>();
+        matches.sort_by(|(score_a, path_a, _), (score_b, path_b, _)| {
+            score_b
+                .cmp(score_a)
+                .then_with(|| path_a.len().cmp(&path_b.len()))
+                .then_with(|| path_a.cmp(path_b))
+        });
+        matches
+            .into_iter()
+            .take(MAX_SYMBOLS)
+            .map(|(_, _, symbol)| symbol.clone())
+            .collect()
+    }
+}
+
+/// Score how well a query matches a dotted attribute path, or return None
+/// if it doesn't match at all. Every character of the query has to appear
+/// in order, and matches which are consecutive or start a word score
+/// higher, so `s.m.enable` and `myappenable` both find
+/// `services.myapp.enable`.
+fn fuzzy_score(query: &str, path: &str) -> Option<usize> {
+    let query = query.to_lowercase().chars().collect::<Vec<_>>();
+    let path = path.chars().collect::<Vec<_>>();
+    if query.is_empty() {
+        return Some(0);
+    }
+    let word_start = |i: usize| {
+        i == 0
+            || matches!(path[i - 1], '.' | '_' | '-')
+            || (path[i - 1].is_lowercase() && path[i].is_uppercase())
+    };
+
+    // best[i] is the best score of matching the query so far with its last
+    // character at path[i]
+    let mut best: Vec<Option<usize>> = vec![None; path.len()];
+    for (j, &wanted) in query.iter().enumerate() {
+        let mut next = vec![None; path.len()];
+        let mut earlier: Option<usize> = None;
+        for (i, c) in path.iter().enumerate() {
+            let previous = if j == 0 {
+                Some(0)
+            } else {
+                let consecutive = i
+                    .checked_sub(1)
+                    .and_then(|i| best[i])
+                    .map(|score| score + 3);
+                earlier.max(consecutive)
+            };
+            if c.to_lowercase().eq(std::iter::once(wanted)) {
+                let bonus = if word_start(i) { 4 } else { 1 };
+                next[i] = previous.map(|score| score + bonus);
+            }
+            if i > 0 {
+                earlier = earlier.max(best[i - 1]);
+            }
+        }
+        best = next;
+    }
+    best.into_iter().flatten().max()
+}